@@ -0,0 +1,1168 @@
+use core::{cmp::min, fmt};
+
+use crate::Buffer;
+
+#[derive(Clone, Copy)]
+pub struct ImageFlags { flags: u32 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgba {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum PidError {
+    UnexpectedEof,
+    InvalidDimensions { width: u32, height: u32 },
+    PixelCountMismatch { expected: usize, got: usize },
+    TruncatedPalette { entries: usize },
+    TooManyColors,
+    InvalidLightCount { count: u32 },
+    // A reader failed for another reason than running out of data.
+    #[cfg(feature = "std")]
+    Io { kind: std::io::ErrorKind },
+}
+
+impl fmt::Display for PidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PidError::UnexpectedEof => write!(f, "unexpected end of PID data"),
+            PidError::InvalidDimensions { width, height } => write!(f, "invalid image dimensions {width}x{height}"),
+            PidError::PixelCountMismatch { expected, got } => write!(f, "expected {expected} pixels but decoded {got}"),
+            PidError::TruncatedPalette { entries } => write!(f, "palette is truncated after {entries} entries"),
+            PidError::TooManyColors => write!(f, "image has more than 256 colors"),
+            PidError::InvalidLightCount { count } => write!(f, "invalid light count {count}"),
+            #[cfg(feature = "std")]
+            PidError::Io { kind } => write!(f, "failed to read PID data: {kind}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PidError {}
+
+pub trait ByteSource {
+    fn next_u8(&mut self) -> Result<u8, PidError>;
+
+    // Number of bytes left, when the source knows it.
+    fn remaining(&self) -> Option<usize> {
+        None
+    }
+
+    fn next_u32_le(&mut self) -> Result<u32, PidError> {
+        Ok(u32::from_le_bytes([self.next_u8()?, self.next_u8()?, self.next_u8()?, self.next_u8()?]))
+    }
+
+    fn next_i32_le(&mut self) -> Result<i32, PidError> {
+        Ok(self.next_u32_le()? as i32)
+    }
+
+    fn read_into(&mut self, bytes: &mut [u8]) -> Result<(), PidError> {
+        for b in bytes {
+            *b = self.next_u8()?;
+        }
+        Ok(())
+    }
+}
+
+pub struct SliceSource<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SliceSource<'a> {
+    pub fn new(data: &'a [u8]) -> SliceSource<'a> {
+        SliceSource { data, offset: 0 }
+    }
+}
+
+impl ByteSource for SliceSource<'_> {
+    fn remaining(&self) -> Option<usize> {
+        Some(self.data.len() - self.offset)
+    }
+
+    fn next_u8(&mut self) -> Result<u8, PidError> {
+        let b = *self.data.get(self.offset).ok_or(PidError::UnexpectedEof)?;
+        self.offset += 1;
+        Ok(b)
+    }
+
+    fn next_u32_le(&mut self) -> Result<u32, PidError> {
+        let bytes = self.data.get(self.offset..self.offset + 4).ok_or(PidError::UnexpectedEof)?;
+        self.offset += 4;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_into(&mut self, bytes: &mut [u8]) -> Result<(), PidError> {
+        let data = self.data.get(self.offset..self.offset + bytes.len()).ok_or(PidError::UnexpectedEof)?;
+        bytes.copy_from_slice(data);
+        self.offset += bytes.len();
+        Ok(())
+    }
+}
+
+/// Decodes a whole PID held in memory.
+///
+/// The pixel buffer is released when the image is dropped. With the `std`
+/// feature it comes from the global allocator; otherwise it is obtained from
+/// the `alloc` function imported from the `env` module and handed back to
+/// `free_memory`, so the host must provide both.
+pub fn decode_pid_bytes(data: &[u8]) -> Result<PidImage, PidError> {
+    decode_pid_from(&mut SliceSource::new(data))
+}
+
+// Decodes images stored back to back until the data runs out. A frame that
+// fails to decode is yielded as an error and ends the iteration, since the
+// start of the next one cannot be known.
+pub struct PidFrames<'a> {
+    source: SliceSource<'a>,
+    failed: bool,
+}
+
+impl Iterator for PidFrames<'_> {
+    type Item = Result<PidImage, PidError>;
+
+    fn next(&mut self) -> Option<Result<PidImage, PidError>> {
+        if self.failed || self.source.remaining() == Some(0) {
+            return None;
+        }
+        let frame = decode_pid_from(&mut self.source);
+        self.failed = frame.is_err();
+        Some(frame)
+    }
+}
+
+/// Iterates over the images stored back to back in `data`.
+///
+/// Each frame owns its pixels like the result of `decode_pid_bytes`, and
+/// is allocated the same way.
+pub fn decode_pid_frames(data: &[u8]) -> PidFrames<'_> {
+    PidFrames { source: SliceSource::new(data), failed: false }
+}
+
+// Reads one byte at a time, so unbuffered readers should be wrapped in a
+// BufReader first.
+#[cfg(feature = "std")]
+pub struct ReadSource<R> {
+    reader: R,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> ReadSource<R> {
+    pub fn new(reader: R) -> ReadSource<R> {
+        ReadSource { reader }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> ByteSource for ReadSource<R> {
+    fn next_u8(&mut self) -> Result<u8, PidError> {
+        let mut byte = [0; 1];
+        self.reader.read_exact(&mut byte).map_err(|error| match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => PidError::UnexpectedEof,
+            kind => PidError::Io { kind },
+        })?;
+        Ok(byte[0])
+    }
+}
+
+#[cfg(feature = "std")]
+pub fn decode_pid_reader<R: std::io::Read>(reader: R) -> Result<PidImage, PidError> {
+    decode_pid_from(&mut ReadSource::new(reader))
+}
+
+impl ImageFlags {
+    pub const TRANSPARENCY: u32 = 0x01;
+    pub const VIDEO_MEMORY: u32 = 0x02;
+    pub const SYSTEM_MEMORY: u32 = 0x04;
+    pub const FLIP_HORIZONTAL: u32 = 0x08;
+    pub const FLIP_VERTICAL: u32 = 0x10;
+    pub const RUN_LENGTH_ENCODING: u32 = 0x20;
+    pub const LIGHTS: u32 = 0x40;
+    pub const PALETTE: u32 = 0x80;
+    // Not part of the original format: marks palettes storing an alpha byte
+    // after each color.
+    pub const PALETTE_ALPHA: u32 = 0x100;
+    // Not part of the original format either: true-color pixels packed in a
+    // little-endian u16, as RGB565 or as RGB555 with the top bit unused.
+    pub const RGB565: u32 = 0x200;
+    pub const RGB555: u32 = 0x400;
+
+    pub fn from_bits(flags: u32) -> ImageFlags {
+        ImageFlags { flags }
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn use_transparency(&self) -> bool {
+        self.flags & ImageFlags::TRANSPARENCY != 0
+    }
+
+    pub fn use_video_memory(&self) -> bool {
+        self.flags & ImageFlags::VIDEO_MEMORY != 0
+    }
+
+    pub fn use_system_memory(&self) -> bool {
+        self.flags & ImageFlags::SYSTEM_MEMORY != 0
+    }
+
+    pub fn is_fliped_horizontally(&self) -> bool {
+        self.flags & ImageFlags::FLIP_HORIZONTAL != 0
+    }
+
+    pub fn is_fliped_vertically(&self) -> bool {
+        self.flags & ImageFlags::FLIP_VERTICAL != 0
+    }
+
+    pub fn compression_method(&self) -> CompressionMethod {
+        if self.flags & ImageFlags::RUN_LENGTH_ENCODING == 0 {
+            CompressionMethod::Default
+        } else {
+            CompressionMethod::RunLengthEncoding
+        }
+    }
+
+    // Palette images store one index per pixel. Without a palette, pixels are
+    // either packed in 16 bits or R, G, B bytes, followed by an A byte when
+    // transparency is used.
+    pub fn pixel_format(&self) -> PixelFormat {
+        if self.has_palette() {
+            PixelFormat::Indexed
+        } else if self.flags & ImageFlags::RGB565 != 0 {
+            PixelFormat::Rgb565
+        } else if self.flags & ImageFlags::RGB555 != 0 {
+            PixelFormat::Rgb555
+        } else if self.use_transparency() {
+            PixelFormat::Rgba32
+        } else {
+            PixelFormat::Rgb24
+        }
+    }
+
+    pub(crate) fn bytes_per_pixel(&self) -> usize {
+        match self.pixel_format() {
+            PixelFormat::Indexed => 1,
+            PixelFormat::Rgb565 | PixelFormat::Rgb555 => 2,
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba32 => 4,
+        }
+    }
+
+    pub(crate) fn source_index(&self) -> SourceIndex {
+        match (self.is_fliped_horizontally(), self.is_fliped_vertically()) {
+            (false, false) => |w, _h, x, y| (y * w + x) as usize,
+            (true, false) => |w, _h, x, y| (y * w + (w - 1 - x)) as usize,
+            (false, true) => |w, h, x, y| ((h - 1 - y) * w + x) as usize,
+            (true, true) => |w, h, x, y| ((h - 1 - y) * w + (w - 1 - x)) as usize,
+        }
+    }
+
+    // Reads one true-color pixel of bytes_per_pixel() bytes.
+    pub(crate) fn color_reader(&self) -> ColorReader {
+        match self.pixel_format() {
+            PixelFormat::Rgb565 => |p| (unpack_rgb565(u16::from_le_bytes([p[0], p[1]])), 255),
+            PixelFormat::Rgb555 => |p| (unpack_rgb555(u16::from_le_bytes([p[0], p[1]])), 255),
+            PixelFormat::Rgba32 => |p| (Rgb { r: p[0], g: p[1], b: p[2] }, p[3]),
+            _ => |p| (Rgb { r: p[0], g: p[1], b: p[2] }, 255),
+        }
+    }
+
+    pub fn has_lights(&self) -> bool {
+        self.flags & ImageFlags::LIGHTS != 0
+    }
+
+    pub fn has_palette(&self) -> bool {
+        self.flags & ImageFlags::PALETTE != 0
+    }
+
+    pub fn has_palette_alpha(&self) -> bool {
+        self.flags & ImageFlags::PALETTE_ALPHA != 0
+    }
+}
+
+// Lists the set flags by name, with any unknown bits in hex.
+impl fmt::Debug for ImageFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [(u32, &str); 11] = [
+            (ImageFlags::TRANSPARENCY, "TRANSPARENCY"),
+            (ImageFlags::VIDEO_MEMORY, "VIDEO_MEMORY"),
+            (ImageFlags::SYSTEM_MEMORY, "SYSTEM_MEMORY"),
+            (ImageFlags::FLIP_HORIZONTAL, "FLIP_HORIZONTAL"),
+            (ImageFlags::FLIP_VERTICAL, "FLIP_VERTICAL"),
+            (ImageFlags::RUN_LENGTH_ENCODING, "RUN_LENGTH_ENCODING"),
+            (ImageFlags::LIGHTS, "LIGHTS"),
+            (ImageFlags::PALETTE, "PALETTE"),
+            (ImageFlags::PALETTE_ALPHA, "PALETTE_ALPHA"),
+            (ImageFlags::RGB565, "RGB565"),
+            (ImageFlags::RGB555, "RGB555"),
+        ];
+        write!(f, "ImageFlags(")?;
+        let mut rest = self.flags;
+        let mut separator = "";
+        for (bit, name) in NAMES {
+            if self.flags & bit != 0 {
+                write!(f, "{separator}{name}")?;
+                separator = " | ";
+                rest &= !bit;
+            }
+        }
+        if rest != 0 {
+            write!(f, "{separator}{rest:#x}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PidHeader {
+    id: i32,
+    flags: ImageFlags,
+    width: u32,
+    height: u32,
+    user_values: [i32; 4],
+}
+
+impl PidHeader {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn flags(&self) -> ImageFlags {
+        self.flags
+    }
+
+    pub fn user_values(&self) -> [i32; 4] {
+        self.user_values
+    }
+
+    // Size in bytes of the decompressed pixel data.
+    pub fn pixels_len(&self) -> usize {
+        self.width as usize * self.height as usize * self.flags.bytes_per_pixel()
+    }
+
+    pub fn meta(&self) -> PidMeta {
+        PidMeta {
+            id: self.id,
+            width: self.width,
+            height: self.height,
+            flags: self.flags.bits(),
+            compression: match self.flags.compression_method() {
+                CompressionMethod::Default => "default",
+                CompressionMethod::RunLengthEncoding => "rle",
+            },
+            user_values: self.user_values,
+            has_palette: self.flags.has_palette(),
+            has_lights: self.flags.has_lights(),
+        }
+    }
+}
+
+// Everything describing an image but its pixels, for cataloguing sprites
+// without carrying their data around.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PidMeta {
+    pub id: i32,
+    pub width: u32,
+    pub height: u32,
+    pub flags: u32,
+    pub compression: &'static str,
+    pub user_values: [i32; 4],
+    pub has_palette: bool,
+    pub has_lights: bool,
+}
+
+// The pixels are owned by the image and released on drop.
+pub struct PidImage {
+    header: PidHeader,
+    pixels: Buffer,
+    palette: Option<[Rgba; 256]>,
+    lights: Option<Lights>,
+    transparent_index: Option<u8>,
+}
+
+// The light block layout is not documented. It is assumed to be a u32 count
+// followed by that many 16-byte records: i32 x, i32 y, u32 radius, then r, g,
+// b and intensity bytes.
+const MAX_LIGHTS: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Light {
+    pub x: i32,
+    pub y: i32,
+    pub radius: u32,
+    pub color: Rgb,
+    pub intensity: u8,
+}
+
+#[derive(Clone, Copy)]
+struct Lights {
+    records: [Light; MAX_LIGHTS],
+    len: usize,
+}
+
+impl PidImage {
+    pub fn header(&self) -> &PidHeader {
+        &self.header
+    }
+
+    pub fn id(&self) -> i32 {
+        self.header.id
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.header.dimensions()
+    }
+
+    pub fn flags(&self) -> ImageFlags {
+        self.header.flags
+    }
+
+    pub fn user_values(&self) -> [i32; 4] {
+        self.header.user_values
+    }
+
+    pub fn meta(&self) -> PidMeta {
+        self.header.meta()
+    }
+
+    // Palette indices for palette images, packed RGB(A) bytes otherwise.
+    pub fn pixels(&self) -> &[u8] {
+        self.pixels.data
+    }
+
+    pub fn palette(&self) -> Option<&[Rgba; 256]> {
+        self.palette.as_ref()
+    }
+
+    pub fn lights(&self) -> Option<&[Light]> {
+        self.lights.as_ref().map(|l| &l.records[..l.len])
+    }
+
+    // The palette index drawn transparent, if any.
+    pub fn transparent_index(&self) -> Option<u8> {
+        self.transparent_index
+    }
+
+    // Always 1: a PID holds a single image. Files packing several back to
+    // back are split into frames by decode_pid_frames.
+    pub fn frame_count(&self) -> u32 {
+        1
+    }
+
+    pub fn pixels_rgba(&self) -> PixelsRgba<'_> {
+        let flags = self.flags();
+        let palette = self.palette.map(|mut colors| {
+            if let Some(index) = self.transparent_index {
+                colors[index as usize] = Rgba { r: 0, g: 0, b: 0, a: 0 };
+            }
+            colors
+        });
+        PixelsRgba {
+            image: self,
+            palette,
+            source_index: flags.source_index(),
+            read_color: flags.color_reader(),
+            bytes_per_pixel: flags.bytes_per_pixel(),
+            x: 0,
+            y: 0,
+        }
+    }
+}
+
+// The pixel buffer and palette are summarized by their size so that large
+// images stay readable.
+impl fmt::Debug for PidImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PidImage")
+            .field("header", &self.header)
+            .field("pixels", &format_args!("[{} bytes]", self.pixels().len()))
+            .field("palette", &format_args!("{}", if self.palette.is_some() { "[256 colors]" } else { "None" }))
+            .field("lights", &self.lights())
+            .finish()
+    }
+}
+
+pub(crate) type SourceIndex = fn(width: u32, height: u32, x: u32, y: u32) -> usize;
+pub(crate) type ColorReader = fn(pixel: &[u8]) -> (Rgb, u8);
+
+// Yields every pixel in display order, top row first, with its coordinates
+// after flipping and its color resolved through the palette, in which the
+// transparent index, if any, has an alpha of 0.
+pub struct PixelsRgba<'a> {
+    image: &'a PidImage,
+    palette: Option<[Rgba; 256]>,
+    source_index: SourceIndex,
+    read_color: ColorReader,
+    bytes_per_pixel: usize,
+    x: u32,
+    y: u32,
+}
+
+impl Iterator for PixelsRgba<'_> {
+    type Item = (u32, u32, Rgba);
+
+    fn next(&mut self) -> Option<(u32, u32, Rgba)> {
+        let (width, height) = self.image.dimensions();
+        if self.y >= height {
+            return None;
+        }
+        let (x, y) = (self.x, self.y);
+        let i = (self.source_index)(width, height, x, y);
+        let color = match &self.palette {
+            Some(colors) => colors[self.image.pixels()[i] as usize],
+            None => {
+                let p = i * self.bytes_per_pixel;
+                let (Rgb { r, g, b }, a) = (self.read_color)(&self.image.pixels()[p..p + self.bytes_per_pixel]);
+                Rgba { r, g, b, a }
+            }
+        };
+        self.x += 1;
+        if self.x == width {
+            self.x = 0;
+            self.y += 1;
+        }
+        Some((x, y, color))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (width, height) = self.image.dimensions();
+        let left = (height - self.y) as usize * width as usize - self.x as usize;
+        (left, Some(left))
+    }
+}
+
+impl ExactSizeIterator for PixelsRgba<'_> {}
+
+// Keeps the RGBA output of the largest accepted image well within the 4 GiB
+// addressable by wasm32.
+pub(crate) const MAX_PIXEL_COUNT: u64 = 8192 * 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat { Indexed, Rgb565, Rgb555, Rgb24, Rgba32 }
+
+// The low bits of each channel repeat its high bits, so that full intensity
+// expands to 255 rather than 248 or 252.
+pub fn unpack_rgb565(pixel: u16) -> Rgb {
+    let (r, g, b) = ((pixel >> 11) as u8 & 0x1F, (pixel >> 5) as u8 & 0x3F, pixel as u8 & 0x1F);
+    Rgb { r: r << 3 | r >> 2, g: g << 2 | g >> 4, b: b << 3 | b >> 2 }
+}
+
+pub fn unpack_rgb555(pixel: u16) -> Rgb {
+    let (r, g, b) = ((pixel >> 10) as u8 & 0x1F, (pixel >> 5) as u8 & 0x1F, pixel as u8 & 0x1F);
+    Rgb { r: r << 3 | r >> 2, g: g << 3 | g >> 2, b: b << 3 | b >> 2 }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionMethod { #[default] Default, RunLengthEncoding }
+
+// Turns the end of the data into None so that callers can stop there, while
+// any other failure of the source is still reported.
+fn until_eof<T>(result: Result<T, PidError>) -> Result<Option<T>, PidError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(PidError::UnexpectedEof) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// The decompressors return how many pixels the stream describes, which only
+// differs from pixels_count for corrupt data. Pixels past pixels_count are
+// dropped rather than written, and a stream ending inside a run or literal
+// stretch stops the count where it started.
+fn decompress_default<S: ByteSource>(data: &mut S, pixels: &mut Buffer, pixels_count: usize) -> Result<usize, PidError> {
+    let mut pixel = 0;
+    while pixel < pixels_count {
+        let n: u8;
+        let b: u8;
+        let Some(a) = until_eof(data.next_u8())? else {
+            break;
+        };
+        if a > 192 {
+            n = a - 192;
+            let Some(byte) = until_eof(data.next_u8())? else {
+                break;
+            };
+            b = byte;
+        } else {
+            n = 1;
+            b = a;
+        }
+        let end = pixel + n as usize;
+        pixels.fill(pixel, min(end, pixels_count), b);
+        pixel = end;
+    }
+    Ok(pixel)
+}
+
+// Skipped runs are written as zero bytes, index 0 for palette images. They
+// are only transparent when the transparency flag says so, like any other
+// index 0 pixel.
+fn decompress_run_length_encoding<S: ByteSource>(data: &mut S, pixels: &mut Buffer, pixels_count: usize) -> Result<usize, PidError> {
+    let mut pixel = 0;
+    'stream: while pixel < pixels_count {
+        let Some(a) = until_eof(data.next_u8())? else {
+            break;
+        };
+        if a == 0 {
+            break;
+        }
+        if a > 128 {
+            let end = pixel + (a - 128) as usize;
+            pixels.fill(pixel, min(end, pixels_count), 0);
+            pixel = end;
+        } else {
+            let end = pixel + a as usize;
+            let kept = min(end, pixels_count);
+            if let Some(bytes) = pixels.data.get_mut(pixel..kept) {
+                if until_eof(data.read_into(bytes))?.is_none() {
+                    break;
+                }
+            }
+            for _ in kept..end {
+                if until_eof(data.next_u8())?.is_none() {
+                    break 'stream;
+                }
+            }
+            pixel = end;
+        }
+    }
+    Ok(pixel)
+}
+
+fn read_lights<S: ByteSource>(cur: &mut S) -> Result<Lights, PidError> {
+    let count = cur.next_u32_le()?;
+    if count as usize > MAX_LIGHTS {
+        return Err(PidError::InvalidLightCount { count });
+    }
+    let black = Rgb { r: 0, g: 0, b: 0 };
+    let mut lights = Lights {
+        records: [Light { x: 0, y: 0, radius: 0, color: black, intensity: 0 }; MAX_LIGHTS],
+        len: count as usize,
+    };
+    for light in &mut lights.records[..lights.len] {
+        light.x = cur.next_i32_le()?;
+        light.y = cur.next_i32_le()?;
+        light.radius = cur.next_u32_le()?;
+        light.color.r = cur.next_u8()?;
+        light.color.g = cur.next_u8()?;
+        light.color.b = cur.next_u8()?;
+        light.intensity = cur.next_u8()?;
+    }
+    Ok(lights)
+}
+
+pub fn decode_pid_header<S: ByteSource>(cur: &mut S) -> Result<PidHeader, PidError> {
+    let id = cur.next_i32_le()?;
+
+    let flags = ImageFlags::from_bits(cur.next_u32_le()?);
+    let width = cur.next_u32_le()?;
+    let height = cur.next_u32_le()?;
+    let mut user_values: [i32; 4] = [0; 4];
+    user_values[0] = cur.next_i32_le()?;
+    user_values[1] = cur.next_i32_le()?;
+    user_values[2] = cur.next_i32_le()?;
+    user_values[3] = cur.next_i32_le()?;
+    if width == 0 || height == 0 || width as u64 * height as u64 > MAX_PIXEL_COUNT {
+        return Err(PidError::InvalidDimensions { width, height });
+    }
+    let header = PidHeader { id, flags, width, height, user_values };
+    // No control byte expands to more than 127 pixels, so a header claiming
+    // more than that per remaining byte is not a PID and is rejected before
+    // anything gets allocated for it.
+    if let Some(remaining) = cur.remaining() {
+        if header.pixels_len() as u64 > remaining as u64 * 127 {
+            return Err(PidError::InvalidDimensions { width, height });
+        }
+    }
+    Ok(header)
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct DecodeOptions {
+    // Keeps the entries of a truncated palette and leaves the missing ones
+    // opaque black instead of failing with TruncatedPalette.
+    pub lenient: bool,
+    // Palette index to draw transparent in place of the flag-driven rule,
+    // under which index 0 is transparent when the image uses transparency.
+    pub transparent_index: Option<u8>,
+}
+
+// Fails with the number of entries read in full when the data runs out.
+// Returns how many entries were read, fewer than 256 when the data ends
+// inside the palette. An entry is only stored once complete, so the one the
+// data ends in keeps its default like those after it.
+fn read_palette<S: ByteSource>(cur: &mut S, palette: &mut [Rgba; 256], with_alpha: bool) -> Result<usize, PidError> {
+    let channels = if with_alpha { 4 } else { 3 };
+    for (i, c) in palette.iter_mut().enumerate() {
+        let mut entry = [c.r, c.g, c.b, c.a];
+        for byte in &mut entry[..channels] {
+            let Some(b) = until_eof(cur.next_u8())? else {
+                return Ok(i);
+            };
+            *byte = b;
+        }
+        *c = Rgba { r: entry[0], g: entry[1], b: entry[2], a: entry[3] };
+    }
+    Ok(palette.len())
+}
+
+// Decodes what follows the header into `pixels`, which must hold exactly
+// header.pixels_len() bytes.
+pub(crate) fn decode_pid_body<S: ByteSource>(cur: &mut S, header: PidHeader, mut pixels: Buffer, options: DecodeOptions) -> Result<PidImage, PidError> {
+    let flags = header.flags;
+    let pixels_count = header.pixels_len();
+    let decoded = match flags.compression_method() {
+        CompressionMethod::Default => decompress_default(cur, &mut pixels, pixels_count)?,
+        CompressionMethod::RunLengthEncoding => decompress_run_length_encoding(cur, &mut pixels, pixels_count)?,
+    };
+    if decoded != pixels_count {
+        return Err(PidError::PixelCountMismatch { expected: pixels_count, got: decoded });
+    }
+
+    let mut truncated = false;
+    let palette = if flags.has_palette() {
+        let mut p: [Rgba; 256] = [Rgba { r: 0, g: 0, b: 0, a: 255 }; 256];
+        let entries = read_palette(cur, &mut p, flags.has_palette_alpha())?;
+        if entries < p.len() {
+            if !options.lenient {
+                return Err(PidError::TruncatedPalette { entries });
+            }
+            truncated = true;
+        }
+        Some(p)
+    } else {
+        None
+    };
+
+    // Nothing is left to read lights from after a truncated palette.
+    let lights = if flags.has_lights() && !truncated {
+        Some(read_lights(cur)?)
+    } else {
+        None
+    };
+    
+    // True-color images have no index to key on.
+    let transparent_index = match options.transparent_index {
+        _ if !flags.has_palette() => None,
+        Some(index) => Some(index),
+        None if flags.use_transparency() => Some(0),
+        None => None,
+    };
+    
+    Ok(PidImage { header, pixels, palette, lights, transparent_index })
+}
+
+pub fn decode_pid_from<S: ByteSource>(cur: &mut S) -> Result<PidImage, PidError> {
+    decode_pid_with(cur, DecodeOptions::default())
+}
+
+/// Decodes a PID from `cur`, overriding the lenient and transparency settings
+/// with `options`.
+///
+/// Like every decoder of this crate, it allocates the pixels through the
+/// global allocator under `std`, or the `alloc` and `free_memory` imports of
+/// the `env` module without it, and releases them once the returned image
+/// goes out of scope.
+pub fn decode_pid_with<S: ByteSource>(cur: &mut S, options: DecodeOptions) -> Result<PidImage, PidError> {
+    let header = decode_pid_header(cur)?;
+    let pixels = Buffer::new(header.pixels_len());
+    decode_pid_body(cur, header, pixels, options)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // Wraps an already compressed body in a header with a zero id and user
+    // values.
+    pub(crate) fn pid(flags: u32, width: u32, height: u32, body: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for u in [0, flags, width, height, 0, 0, 0, 0] {
+            data.extend_from_slice(&u.to_le_bytes());
+        }
+        data.extend_from_slice(body);
+        data
+    }
+
+    // A 256-entry RGB palette starting with `colors` and padded with black.
+    pub(crate) fn palette(colors: &[[u8; 3]]) -> Vec<u8> {
+        let mut data: Vec<u8> = colors.concat();
+        data.resize(256 * 3, 0);
+        data
+    }
+
+    // A palette image whose entry i is the gray 10 * i, so that the rendered
+    // red channel tells which index a pixel came from.
+    pub(crate) fn gray_pid(flags: u32, width: u32, height: u32, stream: &[u8]) -> Vec<u8> {
+        let grays: Vec<[u8; 3]> = (0..16).map(|i| [10 * i; 3]).collect();
+        pid(ImageFlags::PALETTE | flags, width, height, &[stream, &palette(&grays)].concat())
+    }
+
+    // Reads one byte at a time and never tells how many are left, like a
+    // stream would.
+    struct ByteWise<'a>(SliceSource<'a>);
+
+    impl ByteSource for ByteWise<'_> {
+        fn next_u8(&mut self) -> Result<u8, PidError> {
+            self.0.next_u8()
+        }
+    }
+
+    fn reds(image: &PidImage) -> Vec<u8> {
+        image.pixels_rgba().map(|(_, _, color)| color.r).collect()
+    }
+
+    fn decompress(method: CompressionMethod, stream: &[u8], pixels_count: usize) -> (usize, Buffer) {
+        let mut pixels = Buffer::new(pixels_count);
+        let mut source = SliceSource::new(stream);
+        let decoded = match method {
+            CompressionMethod::Default => decompress_default(&mut source, &mut pixels, pixels_count),
+            CompressionMethod::RunLengthEncoding => decompress_run_length_encoding(&mut source, &mut pixels, pixels_count),
+        };
+        (decoded.unwrap(), pixels)
+    }
+
+    #[test]
+    fn default_expands_runs() {
+        let (decoded, pixels) = decompress(CompressionMethod::Default, &[0xC3, 7, 0xC1, 5], 4);
+        assert_eq!(decoded, 4);
+        assert_eq!(pixels.data, [7, 7, 7, 5]);
+    }
+
+    #[test]
+    fn default_copies_literals() {
+        let (decoded, pixels) = decompress(CompressionMethod::Default, &[1, 2, 192], 3);
+        assert_eq!(decoded, 3);
+        assert_eq!(pixels.data, [1, 2, 192]);
+    }
+
+    #[test]
+    fn default_counts_run_overrun() {
+        let (decoded, pixels) = decompress(CompressionMethod::Default, &[4, 0xC5, 9], 3);
+        assert_eq!(decoded, 6);
+        assert_eq!(pixels.data, [4, 9, 9]);
+    }
+
+    #[test]
+    fn run_length_encoding_drops_literals_past_the_image() {
+        let mut pixels = Buffer::new(3);
+        let mut source = SliceSource::new(&[0x82, 3, 7, 8, 9]);
+        assert_eq!(decompress_run_length_encoding(&mut source, &mut pixels, 3).unwrap(), 5);
+        assert_eq!(pixels.data, [0, 0, 7]);
+        assert_eq!(source.remaining(), Some(0));
+    }
+
+    #[test]
+    fn run_length_encoding_skips_with_zeros() {
+        let (decoded, pixels) = decompress(CompressionMethod::RunLengthEncoding, &[0x82, 2, 4, 5], 4);
+        assert_eq!(decoded, 4);
+        assert_eq!(pixels.data, [0, 0, 4, 5]);
+    }
+
+    #[test]
+    fn run_length_encoding_copies_literals() {
+        let (decoded, pixels) = decompress(CompressionMethod::RunLengthEncoding, &[3, 200, 1, 129], 3);
+        assert_eq!(decoded, 3);
+        assert_eq!(pixels.data, [200, 1, 129]);
+    }
+
+    #[test]
+    fn run_length_encoding_stops_at_zero_byte() {
+        let (decoded, pixels) = decompress(CompressionMethod::RunLengthEncoding, &[1, 7, 0, 2, 8, 8], 3);
+        assert_eq!(decoded, 1);
+        assert_eq!(pixels.data[0], 7);
+    }
+
+    #[test]
+    fn eof_inside_run_is_pixel_count_mismatch() {
+        let data = pid(ImageFlags::PALETTE, 4, 1, &[0xC2, 3, 0xC2]);
+        let error = decode_pid_bytes(&data).unwrap_err();
+        assert!(matches!(error, PidError::PixelCountMismatch { expected: 4, got: 2 }));
+    }
+
+    #[test]
+    fn eof_inside_dropped_literals_is_pixel_count_mismatch() {
+        let data = pid(ImageFlags::PALETTE | ImageFlags::RUN_LENGTH_ENCODING, 2, 1, &[0x81, 3, 5, 6]);
+        let error = decode_pid_bytes(&data).unwrap_err();
+        assert!(matches!(error, PidError::PixelCountMismatch { expected: 2, got: 1 }));
+    }
+
+    #[test]
+    fn eof_inside_literal_is_pixel_count_mismatch() {
+        let data = pid(ImageFlags::PALETTE | ImageFlags::RUN_LENGTH_ENCODING, 4, 1, &[0x81, 3, 5, 6]);
+        let error = decode_pid_bytes(&data).unwrap_err();
+        assert!(matches!(error, PidError::PixelCountMismatch { expected: 4, got: 1 }));
+    }
+
+    #[test]
+    fn transparent_index_needs_a_palette() {
+        let flags = ImageFlags::PALETTE | ImageFlags::TRANSPARENCY;
+        let data = pid(flags, 1, 1, &[&[1][..], &palette(&[])].concat());
+        assert_eq!(decode_pid_bytes(&data).unwrap().transparent_index(), Some(0));
+        let options = DecodeOptions { transparent_index: Some(255), ..DecodeOptions::default() };
+        let image = decode_pid_with(&mut SliceSource::new(&data), options).unwrap();
+        assert_eq!(image.transparent_index(), Some(255));
+
+        let data = pid(ImageFlags::TRANSPARENCY, 1, 1, &[3, 0, 0, 0]);
+        assert_eq!(decode_pid_bytes(&data).unwrap().transparent_index(), None);
+        let image = decode_pid_with(&mut SliceSource::new(&data), options).unwrap();
+        assert_eq!(image.transparent_index(), None);
+    }
+
+    #[test]
+    fn frames_end_after_first_error() {
+        let palette = palette(&[]);
+        let data = [
+            pid(ImageFlags::PALETTE, 1, 1, &[&[1][..], &palette].concat()),
+            pid(ImageFlags::PALETTE, 2, 1, &[&[0xC2, 2][..], &palette].concat()),
+            pid(ImageFlags::PALETTE, 0, 1, &[]),
+            pid(ImageFlags::PALETTE, 1, 1, &[&[1][..], &palette].concat()),
+        ]
+        .concat();
+        let mut frames = decode_pid_frames(&data);
+        let first = frames.next().unwrap().unwrap();
+        assert_eq!((first.pixels(), first.frame_count()), (&[1][..], 1));
+        assert_eq!(frames.next().unwrap().unwrap().pixels(), [2, 2]);
+        assert!(frames.next().unwrap().is_err());
+        assert!(frames.next().is_none());
+    }
+
+    #[test]
+    fn horizontal_flip_mirrors_rows() {
+        let image = decode_pid_bytes(&gray_pid(ImageFlags::FLIP_HORIZONTAL, 3, 2, &[1, 2, 3, 4, 5, 6])).unwrap();
+        assert_eq!(reds(&image), [30, 20, 10, 60, 50, 40]);
+        let (x, y, _) = image.pixels_rgba().nth(4).unwrap();
+        assert_eq!((x, y), (1, 1));
+    }
+
+    #[test]
+    fn vertical_flip_reverses_rows() {
+        let image = decode_pid_bytes(&gray_pid(ImageFlags::FLIP_VERTICAL, 3, 2, &[1, 2, 3, 4, 5, 6])).unwrap();
+        assert_eq!(reds(&image), [40, 50, 60, 10, 20, 30]);
+    }
+
+    #[test]
+    fn both_flips_rotate_half_turn() {
+        let flags = ImageFlags::FLIP_HORIZONTAL | ImageFlags::FLIP_VERTICAL;
+        let image = decode_pid_bytes(&gray_pid(flags, 3, 2, &[1, 2, 3, 4, 5, 6])).unwrap();
+        assert_eq!(reds(&image), [60, 50, 40, 30, 20, 10]);
+    }
+
+    // Storing the flipped rendering with the same flag flips it back, index
+    // 0 pixels staying transparent along the way.
+    #[test]
+    fn flipping_twice_restores_the_original() {
+        let stream = [0, 1, 2, 3, 0, 5];
+        let original = decode_pid_bytes(&gray_pid(ImageFlags::TRANSPARENCY, 3, 2, &stream)).unwrap();
+        for flip in [ImageFlags::FLIP_HORIZONTAL, ImageFlags::FLIP_VERTICAL, ImageFlags::FLIP_HORIZONTAL | ImageFlags::FLIP_VERTICAL] {
+            let flags = ImageFlags::TRANSPARENCY | flip;
+            let flipped: Vec<u8> = reds(&decode_pid_bytes(&gray_pid(flags, 3, 2, &stream)).unwrap()).iter().map(|r| r / 10).collect();
+            let restored = decode_pid_bytes(&gray_pid(flags, 3, 2, &flipped)).unwrap();
+            assert_eq!(restored.pixels_rgba().collect::<Vec<_>>(), original.pixels_rgba().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn true_color_pixels_are_decoded() {
+        let image = decode_pid_bytes(&pid(0, 2, 1, &[10, 20, 30, 40, 50, 60])).unwrap();
+        assert!(image.palette().is_none());
+        let colors: Vec<Rgba> = image.pixels_rgba().map(|(_, _, color)| color).collect();
+        assert_eq!(colors, [Rgba { r: 10, g: 20, b: 30, a: 255 }, Rgba { r: 40, g: 50, b: 60, a: 255 }]);
+
+        let image = decode_pid_bytes(&pid(ImageFlags::TRANSPARENCY, 2, 1, &[1, 2, 3, 0, 4, 5, 6, 0xC1, 128])).unwrap();
+        let colors: Vec<Rgba> = image.pixels_rgba().map(|(_, _, color)| color).collect();
+        assert_eq!(colors, [Rgba { r: 1, g: 2, b: 3, a: 0 }, Rgba { r: 4, g: 5, b: 6, a: 128 }]);
+    }
+
+    #[test]
+    fn run_length_encoding_zero_byte_is_pixel_count_mismatch() {
+        let data = gray_pid(ImageFlags::RUN_LENGTH_ENCODING, 3, 1, &[1, 5, 0, 0, 0]);
+        let error = decode_pid_bytes(&data).unwrap_err();
+        assert!(matches!(error, PidError::PixelCountMismatch { expected: 3, got: 1 }));
+    }
+
+    #[test]
+    fn runs_past_the_image_are_pixel_count_mismatch() {
+        for (flags, stream) in [(0, &[0xC5, 1][..]), (ImageFlags::RUN_LENGTH_ENCODING, &[0x85]), (ImageFlags::RUN_LENGTH_ENCODING, &[5, 1, 2, 3, 4, 5])] {
+            let error = decode_pid_bytes(&gray_pid(flags, 3, 1, stream)).unwrap_err();
+            assert!(matches!(error, PidError::PixelCountMismatch { expected: 3, got: 5 }));
+        }
+    }
+
+    #[test]
+    fn errors_display_their_details() {
+        let messages = [
+            (PidError::UnexpectedEof, "unexpected end of PID data"),
+            (PidError::InvalidDimensions { width: 0, height: 3 }, "invalid image dimensions 0x3"),
+            (PidError::PixelCountMismatch { expected: 6, got: 4 }, "expected 6 pixels but decoded 4"),
+            (PidError::TruncatedPalette { entries: 233 }, "palette is truncated after 233 entries"),
+            (PidError::TooManyColors, "image has more than 256 colors"),
+            (PidError::InvalidLightCount { count: 17 }, "invalid light count 17"),
+        ];
+        for (error, message) in messages {
+            assert_eq!(std::format!("{error}"), message);
+        }
+    }
+
+    #[test]
+    fn wrapping_dimensions_are_rejected() {
+        let error = decode_pid_bytes(&gray_pid(0, 0x10000, 0x10000, &[])).unwrap_err();
+        assert!(matches!(error, PidError::InvalidDimensions { width: 0x10000, height: 0x10000 }));
+    }
+
+    #[test]
+    fn header_fields_are_little_endian() {
+        let mut data = Vec::new();
+        for i in [-7, ImageFlags::PALETTE as i32, 1, 1, 1, -2, 0x01020304, i32::MIN] {
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+        let header = decode_pid_header(&mut SliceSource::new(&[&data[..], &[1]].concat())).unwrap();
+        assert_eq!(header.id(), -7);
+        assert_eq!(header.user_values(), [1, -2, 0x01020304, i32::MIN]);
+    }
+
+    #[test]
+    fn short_stream_is_pixel_count_mismatch() {
+        let error = decode_pid_bytes(&pid(ImageFlags::PALETTE, 4, 1, &[1, 2])).unwrap_err();
+        assert!(matches!(error, PidError::PixelCountMismatch { expected: 4, got: 2 }));
+        let error = decode_pid_bytes(&pid(ImageFlags::PALETTE | ImageFlags::RUN_LENGTH_ENCODING, 4, 1, &[0x83])).unwrap_err();
+        assert!(matches!(error, PidError::PixelCountMismatch { expected: 4, got: 3 }));
+    }
+
+    #[test]
+    fn zero_dimensions_are_rejected() {
+        for (width, height) in [(0, 5), (5, 0)] {
+            let error = decode_pid_bytes(&gray_pid(0, width, height, &[1])).unwrap_err();
+            assert!(matches!(error, PidError::InvalidDimensions { width: w, height: h } if (w, h) == (width, height)));
+        }
+    }
+
+    #[test]
+    fn high_color_pixels_are_unpacked() {
+        assert_eq!(unpack_rgb565(0xFFFF), Rgb { r: 255, g: 255, b: 255 });
+        assert_eq!(unpack_rgb555(0x801F), Rgb { r: 0, g: 0, b: 255 });
+        let image = decode_pid_bytes(&pid(ImageFlags::RGB565, 2, 1, &[0xC1, 0xE0, 7, 0, 0xC1, 0xF8])).unwrap();
+        assert_eq!(image.flags().pixel_format(), PixelFormat::Rgb565);
+        let colors: Vec<Rgba> = image.pixels_rgba().map(|(_, _, color)| color).collect();
+        assert_eq!(colors, [Rgba { r: 0, g: 255, b: 0, a: 255 }, Rgba { r: 255, g: 0, b: 0, a: 255 }]);
+        let image = decode_pid_bytes(&pid(ImageFlags::RGB555, 2, 1, &[0, 0x7C, 0xC1, 0xE0, 3])).unwrap();
+        assert_eq!(image.flags().pixel_format(), PixelFormat::Rgb555);
+        let colors: Vec<Rgba> = image.pixels_rgba().map(|(_, _, color)| color).collect();
+        assert_eq!(colors, [Rgba { r: 255, g: 0, b: 0, a: 255 }, Rgba { r: 0, g: 255, b: 0, a: 255 }]);
+    }
+
+    #[test]
+    fn truncated_palette_is_rejected_unless_lenient() {
+        let bytes: Vec<u8> = (0..700).map(|i| i as u8).collect();
+        let data = pid(ImageFlags::PALETTE, 1, 1, &[&[5][..], &bytes].concat());
+        let error = decode_pid_bytes(&data).unwrap_err();
+        assert!(matches!(error, PidError::TruncatedPalette { entries: 233 }));
+
+        let options = DecodeOptions { lenient: true, ..DecodeOptions::default() };
+        let image = decode_pid_with(&mut SliceSource::new(&data), options).unwrap();
+        let palette = image.palette().unwrap();
+        assert_eq!(palette[5], Rgba { r: 15, g: 16, b: 17, a: 255 });
+        assert_eq!(palette[232], Rgba { r: 184, g: 185, b: 186, a: 255 });
+        assert_eq!(palette[233], Rgba { r: 0, g: 0, b: 0, a: 255 });
+        assert_eq!(palette[255], Rgba { r: 0, g: 0, b: 0, a: 255 });
+    }
+
+    #[test]
+    fn lights_follow_the_palette() {
+        let mut record = Vec::new();
+        for i in [1i32, -3, 4, 50] {
+            record.extend_from_slice(&i.to_le_bytes());
+        }
+        record.extend_from_slice(&[255, 128, 0, 9]);
+        let flags = ImageFlags::PALETTE | ImageFlags::LIGHTS;
+        let image = decode_pid_bytes(&pid(flags, 1, 1, &[&[1][..], &palette(&[]), &record].concat())).unwrap();
+        let light = image.lights().unwrap()[0];
+        assert_eq!((light.x, light.y, light.radius, light.intensity), (-3, 4, 50, 9));
+        assert_eq!(light.color, Rgb { r: 255, g: 128, b: 0 });
+
+        let error = decode_pid_bytes(&pid(flags, 1, 1, &[&[1][..], &palette(&[]), &17u32.to_le_bytes()].concat())).unwrap_err();
+        assert!(matches!(error, PidError::InvalidLightCount { count: 17 }));
+    }
+
+    #[test]
+    fn pixel_count_is_capped() {
+        let header = decode_pid_header(&mut ByteWise(SliceSource::new(&pid(0, 8192, 8192, &[]))));
+        assert_eq!(header.unwrap().pixels_len(), 3 * 8192 * 8192);
+        let error = decode_pid_header(&mut ByteWise(SliceSource::new(&pid(0, 8193, 8192, &[])))).unwrap_err();
+        assert!(matches!(error, PidError::InvalidDimensions { width: 8193, height: 8192 }));
+        let error = decode_pid_bytes(&pid(0, u32::MAX, 2, &[])).unwrap_err();
+        assert!(matches!(error, PidError::InvalidDimensions { width: u32::MAX, height: 2 }));
+    }
+
+    #[test]
+    fn slice_and_byte_wise_sources_decode_alike() {
+        let literals: Vec<u8> = (1..=128).collect();
+        let images = [
+            gray_pid(0, 70, 2, &[0xFF, 3, 0xC7, 4, 5, 6, 0xFF, 7, 0xC5, 8]),
+            gray_pid(ImageFlags::RUN_LENGTH_ENCODING, 136, 1, &[&[0x85, 128][..], &literals, &[0x83]].concat()),
+        ];
+        for data in images {
+            let fast = decode_pid_bytes(&data).unwrap();
+            let byte_wise = decode_pid_from(&mut ByteWise(SliceSource::new(&data))).unwrap();
+            assert_eq!(fast.pixels(), byte_wise.pixels());
+            assert_eq!(fast.palette(), byte_wise.palette());
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn reader_failures_are_not_truncation() {
+        struct Denied;
+
+        impl std::io::Read for Denied {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::PermissionDenied.into())
+            }
+        }
+
+        let error = decode_pid_reader(Denied).unwrap_err();
+        assert!(matches!(error, PidError::Io { kind: std::io::ErrorKind::PermissionDenied }));
+        let data = gray_pid(0, 2, 1, &[]);
+        let error = decode_pid_reader(std::io::Read::chain(&data[..32], Denied)).unwrap_err();
+        assert!(matches!(error, PidError::Io { kind: std::io::ErrorKind::PermissionDenied }));
+        let error = decode_pid_reader(&data[..20]).unwrap_err();
+        assert!(matches!(error, PidError::UnexpectedEof));
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture` to compare the
+    // slice fast path with byte-wise reads on a 1024x1024 RLE stream.
+    #[test]
+    #[ignore]
+    fn time_run_length_decoding() {
+        // Each block skips 28 pixels then copies 100, 128 pixels in all.
+        let literals: Vec<u8> = (1..=100).collect();
+        let stream: Vec<u8> = (0..8192).flat_map(|_| [&[0x80 + 28, 100][..], &literals].concat()).collect();
+        let data = gray_pid(ImageFlags::RUN_LENGTH_ENCODING, 1024, 1024, &stream);
+        let start = std::time::Instant::now();
+        let fast = decode_pid_bytes(&data).unwrap();
+        let fast_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let byte_wise = decode_pid_from(&mut ByteWise(SliceSource::new(&data))).unwrap();
+        let byte_wise_time = start.elapsed();
+        assert_eq!(fast.pixels(), byte_wise.pixels());
+        std::println!("1024x1024: slices {fast_time:?}, byte-wise {byte_wise_time:?}");
+    }
+}
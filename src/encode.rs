@@ -0,0 +1,357 @@
+use crate::Buffer;
+use crate::decode::{CompressionMethod, ImageFlags, PidError, Rgb, MAX_PIXEL_COUNT};
+use crate::quantize::median_cut;
+
+const HEADER_SIZE: usize = 32;
+const PALETTE_SIZE: usize = 256 * 3;
+
+#[derive(Clone, Copy, Default)]
+pub struct EncodeOptions {
+    pub id: i32,
+    pub user_values: [i32; 4],
+    // None compresses with both methods and keeps the smaller stream.
+    pub compression: Option<CompressionMethod>,
+    // Caps the palette size, the transparent index included. None allows all
+    // 256 entries. Images with more colors are quantized.
+    pub max_colors: Option<usize>,
+}
+
+// Without a buffer the writer only counts bytes, which lets the encoder size
+// its output allocation exactly before writing it.
+struct PidWriter<'a> {
+    buffer: Option<&'a mut Buffer>,
+    len: usize,
+}
+
+impl PidWriter<'_> {
+    fn counter() -> PidWriter<'static> {
+        PidWriter { buffer: None, len: 0 }
+    }
+
+    fn push_u8(&mut self, b: u8) {
+        if let Some(buffer) = self.buffer.as_deref_mut() {
+            buffer.write_u8(self.len, b);
+        }
+        self.len += 1;
+    }
+
+    fn push_u32_le(&mut self, u: u32) {
+        if let Some(buffer) = self.buffer.as_deref_mut() {
+            buffer.write_u32_le(self.len, u);
+        }
+        self.len += 4;
+    }
+
+    fn push_i32_le(&mut self, i: i32) {
+        self.push_u32_le(i as u32);
+    }
+}
+
+// An encoded PID, released when dropped.
+pub struct EncodedPid {
+    buffer: Buffer,
+    payload_len: usize,
+}
+
+impl EncodedPid {
+    pub fn bytes(&self) -> &[u8] {
+        self.buffer.data
+    }
+
+    // Size of the compressed pixel stream, header and palette excluded.
+    pub fn payload_len(&self) -> usize {
+        self.payload_len
+    }
+
+    pub(crate) fn into_raw(self) -> &'static mut [u8] {
+        self.buffer.into_raw()
+    }
+}
+
+struct Palette {
+    colors: [Rgb; 256],
+    len: usize,
+    capacity: usize,
+}
+
+impl Palette {
+    // Entries below `first` are reserved and never matched.
+    fn index_of(&mut self, color: Rgb, first: usize) -> Result<u8, PidError> {
+        if let Some(i) = self.colors[first..self.len].iter().position(|c| *c == color) {
+            return Ok((first + i) as u8);
+        }
+        if self.len == self.capacity {
+            return Err(PidError::TooManyColors);
+        }
+        self.colors[self.len] = color;
+        self.len += 1;
+        Ok((self.len - 1) as u8)
+    }
+
+    fn nearest(&self, color: Rgb, first: usize) -> u8 {
+        let distance = |c: &Rgb| {
+            let (r, g, b) = (c.r as i32 - color.r as i32, c.g as i32 - color.g as i32, c.b as i32 - color.b as i32);
+            r * r + g * g + b * b
+        };
+        let nearest = (first..self.len).min_by_key(|&i| distance(&self.colors[i]));
+        nearest.unwrap_or(first) as u8
+    }
+}
+
+// Runs of up to 63 pixels become a 192 + length header followed by the index.
+// Single indices are written as-is, except those above 192 which would read as
+// a run header and are written as a run of one instead.
+fn compress_default(indices: &[u8], out: &mut PidWriter) {
+    let mut i = 0;
+    while i < indices.len() {
+        let b = indices[i];
+        let run = indices[i..].iter().take(63).take_while(|&&c| c == b).count();
+        if run == 1 && b <= 192 {
+            out.push_u8(b);
+        } else {
+            out.push_u8(192 + run as u8);
+            out.push_u8(b);
+        }
+        i += run;
+    }
+}
+
+// Runs of up to 127 zero indices become a 128 + length header. Other indices
+// are written in stretches of up to 128, each preceded by its length.
+fn compress_run_length_encoding(indices: &[u8], out: &mut PidWriter) {
+    let mut i = 0;
+    while i < indices.len() {
+        let rest = &indices[i..];
+        let zeros = rest.iter().take(127).take_while(|&&c| c == 0).count();
+        if zeros > 0 {
+            out.push_u8(128 + zeros as u8);
+            i += zeros;
+        } else {
+            let literals = rest.iter().take(128).take_while(|&&c| c != 0).count();
+            out.push_u8(literals as u8);
+            for &b in &rest[..literals] {
+                out.push_u8(b);
+            }
+            i += literals;
+        }
+    }
+}
+
+fn compress(method: CompressionMethod, indices: &[u8], out: &mut PidWriter) {
+    match method {
+        CompressionMethod::Default => compress_default(indices, out),
+        CompressionMethod::RunLengthEncoding => compress_run_length_encoding(indices, out),
+    }
+}
+
+fn compressed_len(method: CompressionMethod, indices: &[u8]) -> usize {
+    let mut counter = PidWriter::counter();
+    compress(method, indices, &mut counter);
+    counter.len
+}
+
+// Fully transparent pixels (alpha 0) are mapped to the reserved index 0 and
+// the transparency flag is set; any other alpha value is treated as opaque.
+// When the opaque colors do not fit in the palette, it is built by median
+// cut instead and every pixel takes the nearest of its colors.
+pub fn encode_pid(rgba: &[u8], width: u32, height: u32, options: EncodeOptions) -> Result<EncodedPid, PidError> {
+    let pixels_count = width as u64 * height as u64;
+    if pixels_count == 0 || pixels_count > MAX_PIXEL_COUNT || rgba.len() as u64 != 4 * pixels_count {
+        return Err(PidError::InvalidDimensions { width, height });
+    }
+    let pixels_count = pixels_count as usize;
+    let transparent = rgba.chunks_exact(4).any(|p| p[3] == 0);
+
+    let capacity = options.max_colors.unwrap_or(256).min(256);
+    let mut palette = Palette { colors: [Rgb { r: 0, g: 0, b: 0 }; 256], len: 0, capacity };
+    let mut flags = ImageFlags::PALETTE;
+    let first = transparent as usize;
+    if transparent {
+        palette.len = 1;
+        flags |= ImageFlags::TRANSPARENCY;
+    }
+    if capacity <= first && rgba.chunks_exact(4).any(|p| p[3] != 0) {
+        return Err(PidError::TooManyColors);
+    }
+    let mut indices = Buffer::new(pixels_count);
+    let exact: Result<(), PidError> = rgba.chunks_exact(4).enumerate().try_for_each(|(i, p)| {
+        let index = if p[3] == 0 {
+            0
+        } else {
+            palette.index_of(Rgb { r: p[0], g: p[1], b: p[2] }, first)?
+        };
+        indices.write_u8(i, index);
+        Ok(())
+    });
+    if exact.is_err() {
+        palette.len = first + median_cut(rgba, &mut palette.colors[first..capacity]);
+        for (i, p) in rgba.chunks_exact(4).enumerate() {
+            let index = if p[3] == 0 {
+                0
+            } else {
+                palette.nearest(Rgb { r: p[0], g: p[1], b: p[2] }, first)
+            };
+            indices.write_u8(i, index);
+        }
+    }
+
+    let compression = options.compression.unwrap_or_else(|| {
+        let run_length_len = compressed_len(CompressionMethod::RunLengthEncoding, indices.data);
+        if run_length_len < compressed_len(CompressionMethod::Default, indices.data) {
+            CompressionMethod::RunLengthEncoding
+        } else {
+            CompressionMethod::Default
+        }
+    });
+    if compression == CompressionMethod::RunLengthEncoding {
+        flags |= ImageFlags::RUN_LENGTH_ENCODING;
+    }
+    let payload_len = compressed_len(compression, indices.data);
+    let size = HEADER_SIZE + payload_len + PALETTE_SIZE;
+    let mut buffer = Buffer::new(size);
+    let mut out = PidWriter { buffer: Some(&mut buffer), len: 0 };
+
+    out.push_i32_le(options.id);
+    out.push_u32_le(flags);
+    out.push_u32_le(width);
+    out.push_u32_le(height);
+    for v in options.user_values {
+        out.push_i32_le(v);
+    }
+    compress(compression, indices.data, &mut out);
+    for c in palette.colors {
+        out.push_u8(c.r);
+        out.push_u8(c.g);
+        out.push_u8(c.b);
+    }
+
+    Ok(EncodedPid { buffer, payload_len })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode_pid_bytes;
+    use std::vec::Vec;
+
+    // A single row of `colors` distinct opaque colors, color i repeated
+    // run(i) times.
+    fn runs(colors: u32, run: impl Fn(u32) -> usize) -> Vec<u8> {
+        (0..colors).flat_map(|i| [[i as u8, (i >> 8) as u8, 1, 255]].repeat(run(i))).flatten().collect()
+    }
+
+    fn decode_rgba(pid: &EncodedPid) -> Vec<u8> {
+        let image = decode_pid_bytes(pid.bytes()).unwrap();
+        image.pixels_rgba().flat_map(|(_, _, c)| [c.r, c.g, c.b, c.a]).collect()
+    }
+
+    fn encode(rgba: &[u8], width: u32, height: u32, compression: Option<CompressionMethod>) -> EncodedPid {
+        encode_pid(rgba, width, height, EncodeOptions { compression, ..EncodeOptions::default() }).unwrap()
+    }
+
+    #[test]
+    fn auto_keeps_smaller_payload() {
+        let rgba: Vec<u8> = (0..256u32).flat_map(|i| if i < 200 { [0, 0, 0, 0] } else { [i as u8, 0, 0, 255] }).collect();
+        let default = encode(&rgba, 16, 16, Some(CompressionMethod::Default));
+        let run_length = encode(&rgba, 16, 16, Some(CompressionMethod::RunLengthEncoding));
+        let auto = encode(&rgba, 16, 16, None);
+        assert!(run_length.payload_len() < default.payload_len());
+        assert_eq!(auto.payload_len(), run_length.payload_len());
+        assert_eq!(auto.bytes(), run_length.bytes());
+        for pid in [default, run_length] {
+            assert_eq!(pid.bytes().len(), HEADER_SIZE + pid.payload_len() + PALETTE_SIZE);
+        }
+    }
+
+    #[test]
+    fn encoded_pid_decodes_to_source() {
+        let rgba = [
+            0, 0, 0, 0, 255, 0, 0, 255, 0, 255, 0, 255,
+            255, 0, 0, 255, 0, 0, 0, 0, 0, 0, 0, 255,
+        ];
+        let options = EncodeOptions { id: 7, user_values: [1, -2, 3, -4], ..EncodeOptions::default() };
+        let pid = encode_pid(&rgba, 3, 2, options).unwrap();
+        let image = decode_pid_bytes(pid.bytes()).unwrap();
+        assert_eq!((image.id(), image.user_values(), image.dimensions()), (7, [1, -2, 3, -4], (3, 2)));
+        assert!(image.flags().has_palette() && image.flags().use_transparency());
+        assert_eq!(decode_rgba(&pid), rgba);
+    }
+
+    #[test]
+    fn encoder_rejects_invalid_input() {
+        let rgba = [0, 0, 0, 0, 1, 2, 3, 255];
+        let error = encode_pid(&rgba, 3, 1, EncodeOptions::default());
+        assert!(matches!(error, Err(PidError::InvalidDimensions { width: 3, height: 1 })));
+        let error = encode_pid(&[], 0, 0, EncodeOptions::default());
+        assert!(matches!(error, Err(PidError::InvalidDimensions { width: 0, height: 0 })));
+        let options = EncodeOptions { max_colors: Some(1), ..EncodeOptions::default() };
+        assert!(matches!(encode_pid(&rgba, 2, 1, options), Err(PidError::TooManyColors)));
+    }
+
+    #[test]
+    fn default_compression_round_trips() {
+        let rgba = runs(250, |i| (i as usize * 7) % 70 + 1);
+        let width = rgba.len() as u32 / 4;
+        let pid = encode(&rgba, width, 1, Some(CompressionMethod::Default));
+        assert_eq!(decode_pid_bytes(pid.bytes()).unwrap().flags().compression_method(), CompressionMethod::Default);
+        assert_eq!(decode_rgba(&pid), rgba);
+    }
+
+    #[test]
+    fn run_length_encoding_round_trips() {
+        let rgba = [[0; 4].repeat(300), runs(200, |_| 1), [0; 4].repeat(5), runs(3, |_| 2)].concat();
+        let width = rgba.len() as u32 / 4;
+        let pid = encode(&rgba, width, 1, Some(CompressionMethod::RunLengthEncoding));
+        let compression = decode_pid_bytes(pid.bytes()).unwrap().flags().compression_method();
+        assert_eq!(compression, CompressionMethod::RunLengthEncoding);
+        assert_eq!(decode_rgba(&pid), rgba);
+    }
+
+    #[test]
+    fn quantized_gradient_stays_close() {
+        let rgba: Vec<u8> = (0..64u32 * 64).flat_map(|i| {
+            let (x, y) = ((i % 64) as u8, (i / 64) as u8);
+            [x * 4, y * 4, (x + y) * 2, 255]
+        }).collect();
+        for (max_colors, tolerance) in [(256, 24), (16, 96)] {
+            let options = EncodeOptions { max_colors: Some(max_colors), ..EncodeOptions::default() };
+            let pid = encode_pid(&rgba, 64, 64, options).unwrap();
+            let worst = decode_rgba(&pid).iter().zip(&rgba).map(|(a, b)| a.abs_diff(*b)).max();
+            assert!(worst.unwrap() <= tolerance);
+        }
+    }
+
+    #[test]
+    fn header_is_written_little_endian() {
+        let options = EncodeOptions { id: -2, user_values: [1, -1, 0x01020304, 0], ..EncodeOptions::default() };
+        let pid = encode_pid(&[1, 2, 3, 255], 1, 1, options).unwrap();
+        assert_eq!(pid.bytes()[..4], (-2i32).to_le_bytes());
+        assert_eq!(pid.bytes()[16..32], [1, 0, 0, 0, 255, 255, 255, 255, 4, 3, 2, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn random_images_round_trip_with_both_methods() {
+        // A small LCG keeps the images reproducible.
+        let mut seed = 0x2545_f491u32;
+        let mut next = move |n: u32| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) % n
+        };
+        for _ in 0..50 {
+            let (width, height) = (next(300) + 1, next(4) + 1);
+            let mut rgba = Vec::new();
+            while rgba.len() < 4 * (width * height) as usize {
+                let color = match next(4) {
+                    0 => [0, 0, 0, 0],
+                    _ => [next(256) as u8, next(3) as u8, 7, 255],
+                };
+                rgba.extend(color.repeat(next(200) as usize + 1));
+            }
+            rgba.truncate(4 * (width * height) as usize);
+            for method in [CompressionMethod::Default, CompressionMethod::RunLengthEncoding] {
+                let options = EncodeOptions { compression: Some(method), ..EncodeOptions::default() };
+                assert_eq!(decode_rgba(&encode_pid(&rgba, width, height, options).unwrap()), rgba);
+            }
+        }
+    }
+}
@@ -1,22 +1,88 @@
 #![no_std]
 
-use core::{panic::PanicInfo, slice};
-
-#[cfg(not(test))]
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+use core::mem::ManuallyDrop;
+use core::{ptr, slice};
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+
+mod decode;
+mod encode;
+mod quantize;
+mod transform;
+
+use decode::decode_pid_body;
+
+pub use decode::{decode_pid_bytes, decode_pid_frames, decode_pid_from, decode_pid_header, decode_pid_with, ByteSource, CompressionMethod, DecodeOptions, ImageFlags, Light, PidError, PidFrames, PidHeader, PidImage, PidMeta, PixelFormat, PixelsRgba, Rgb, Rgba, SliceSource};
+pub use decode::{unpack_rgb555, unpack_rgb565};
+#[cfg(feature = "std")]
+pub use decode::{decode_pid_reader, ReadSource};
+pub use encode::{encode_pid, EncodeOptions, EncodedPid};
+pub use transform::{apply_transforms, composite_over, invert_rgba, luma_alpha, map_channels, premultiply_rgba, rotate_rgba, scale_rgba, Rotation, Transforms};
+#[cfg(feature = "std")]
+pub use transform::gamma_table;
+
+#[cfg(not(any(test, feature = "std")))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(_info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
 
-#[derive(Clone, Copy)]
-struct ImageFlags { flags: u32 }
-
+// Every import below is required to instantiate the module, put_image_data
+// included. Hosts that only use the pointer-returning exports can pass a
+// function that does nothing for it.
 #[link(wasm_import_module = "env")]
 extern "C" {
     fn get_pid_data_u8(offset: u32) -> u8;
     fn get_pid_data_u32_le(offset: u32) -> u32;
     fn get_pid_data_i32_le(offset: u32) -> i32;
+    fn get_pid_data_len() -> u32;
+    fn get_pid_data_ptr() -> *const u8;
+    #[cfg(not(feature = "std"))]
     fn alloc(size: u32) -> *mut u8;
+    #[cfg(not(feature = "std"))]
+    fn free_memory(ptr: *mut u8, size: u32);
+    fn put_image_data(ptr: *const u8, len: u32);
+}
+
+static LAST_IMAGE_LEN: AtomicU32 = AtomicU32::new(0);
+static LAST_PID_LEN: AtomicU32 = AtomicU32::new(0);
+static TRANSPARENT_INDEX: AtomicI32 = AtomicI32::new(-1);
+static TRANSPARENT_COLOR: AtomicI32 = AtomicI32::new(-1);
+static LENIENT: AtomicBool = AtomicBool::new(false);
+static DECODE_RESULT: [AtomicU32; 3] = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+
+// Buffers come from the host through the env imports, or from the global
+// allocator under std so that native binaries need not provide them.
+#[cfg(not(feature = "std"))]
+unsafe fn alloc_bytes(size: usize) -> *mut u8 {
+    unsafe { alloc(size as u32) }
+}
+
+#[cfg(not(feature = "std"))]
+unsafe fn free_bytes(ptr: *mut u8, size: usize) {
+    unsafe { free_memory(ptr, size as u32) };
+}
+
+#[cfg(feature = "std")]
+unsafe fn alloc_bytes(size: usize) -> *mut u8 {
+    if size == 0 {
+        return ptr::NonNull::dangling().as_ptr();
+    }
+    let layout = std::alloc::Layout::array::<u8>(size).unwrap();
+    let ptr = unsafe { std::alloc::alloc(layout) };
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(layout);
+    }
+    ptr
+}
+
+#[cfg(feature = "std")]
+unsafe fn free_bytes(ptr: *mut u8, size: usize) {
+    if size > 0 {
+        unsafe { std::alloc::dealloc(ptr, std::alloc::Layout::array::<u8>(size).unwrap()) };
+    }
 }
 
 struct Buffer {
@@ -26,29 +92,50 @@ struct Buffer {
 impl Buffer {
     fn new(size: usize) -> Buffer {
         Buffer { 
-            data:  unsafe { slice::from_raw_parts_mut(alloc(size as u32), size) }
+            data:  unsafe { slice::from_raw_parts_mut(alloc_bytes(size), size) }
         }
     }
 
+    // Hands the memory over to the caller, who becomes responsible for
+    // releasing it through free_bytes.
+    fn into_raw(self) -> &'static mut [u8] {
+        let mut buffer = ManuallyDrop::new(self);
+        let (ptr, len) = (buffer.data.as_mut_ptr(), buffer.data.len());
+        unsafe { slice::from_raw_parts_mut(ptr, len) }
+    }
+
     fn write_u8(&mut self, n: usize, b: u8) {
-        self.data[n] = b;
+        if let Some(byte) = self.data.get_mut(n) {
+            *byte = b;
+        }
+    }
+
+    fn fill(&mut self, start: usize, end: usize, b: u8) {
+        if let Some(bytes) = self.data.get_mut(start..end) {
+            bytes.fill(b);
+        }
     }
 
     fn write_u32_le(&mut self, n: usize, u: u32) {
-        let bytes = u.to_le_bytes();
-        for i in 0..4 {
-            self.data[n + i] = bytes[i];
+        if let Some(bytes) = self.data.get_mut(n..n + 4) {
+            bytes.copy_from_slice(&u.to_le_bytes());
         }
     }
 }
 
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { free_bytes(self.data.as_mut_ptr(), self.data.len()) };
+    }
+}
+
 struct OutputImage {
     buffer: Buffer,
 }
 
 impl OutputImage {
     fn from_canvas_with_dimensions(width: u32, height: u32) -> OutputImage {
-        let mut data = Buffer::new(4 * (2 + width * height) as usize);
+        let mut data = Buffer::new(4 * (2 + width as usize * height as usize));
         data.write_u32_le(0, width);
         data.write_u32_le(4, height);
         OutputImage { buffer: data }
@@ -63,179 +150,478 @@ impl OutputImage {
     }
 }
 
-#[derive(Clone, Copy)]
-struct Rgb {
-    r: u8,
-    g: u8,
-    b: u8
-}
-
 struct PidDataCursor {
     offset: u32,
+    len: u32,
 }
 
 impl PidDataCursor {
-    fn next_u8(&mut self) -> u8 {
-        let output = unsafe { get_pid_data_u8(self.offset) };
-        self.offset += 1;
-        output
+    fn new() -> PidDataCursor {
+        PidDataCursor { offset: 0, len: unsafe { get_pid_data_len() } }
     }
 
-    fn next_u32_le(&mut self) -> u32 {
-        let output = unsafe { get_pid_data_u32_le(self.offset) };
-        self.offset += 4;
-        output
+    fn advance(&mut self, size: u32) -> Result<u32, PidError> {
+        let offset = self.offset;
+        if self.len - offset < size {
+            return Err(PidError::UnexpectedEof);
+        }
+        self.offset += size;
+        Ok(offset)
+    }
+}
+
+impl ByteSource for PidDataCursor {
+    fn remaining(&self) -> Option<usize> {
+        Some((self.len - self.offset) as usize)
     }
 
-    fn next_i32_le(&mut self) -> i32 {
-        let output = unsafe { get_pid_data_i32_le(self.offset) };
-        self.offset += 4;
-        output
+    fn next_u8(&mut self) -> Result<u8, PidError> {
+        let offset = self.advance(1)?;
+        Ok(unsafe { get_pid_data_u8(offset) })
+    }
+
+    fn next_u32_le(&mut self) -> Result<u32, PidError> {
+        let offset = self.advance(4)?;
+        Ok(unsafe { get_pid_data_u32_le(offset) })
+    }
+
+    fn next_i32_le(&mut self) -> Result<i32, PidError> {
+        let offset = self.advance(4)?;
+        Ok(unsafe { get_pid_data_i32_le(offset) })
     }
 }
 
-impl ImageFlags {
-    fn use_transparency(&self) -> bool {
-        self.flags & 0x01 != 0
+// The PID bytes, when the host has copied them into linear memory. Otherwise
+// they are read through the per-byte imports of PidDataCursor.
+fn host_pid_data() -> Option<&'static [u8]> {
+    let data = unsafe { get_pid_data_ptr() };
+    if data.is_null() {
+        None
+    } else {
+        Some(unsafe { slice::from_raw_parts(data, get_pid_data_len() as usize) })
     }
+}
 
-    fn use_video_memory(&self) -> bool {
-        self.flags & 0x02 != 0
+pub fn decode_pid() -> Result<PidImage, PidError> {
+    match host_pid_data() {
+        Some(data) => decode_pid_bytes(data),
+        None => decode_pid_from(&mut PidDataCursor::new()),
     }
+}
+
+/// Images returned by `write_pid_to_canvas_image_data` belong to the host, which
+/// should call `dealloc(ptr, last_image_len())` once it has copied the
+/// pixels out.
+///
+/// # Safety
+///
+/// `ptr` and `size` must describe an image returned by this module that has not
+/// been released yet.
+#[export_name = "dealloc"]
+pub unsafe extern "C" fn dealloc(ptr: *mut u8, size: u32) {
+    unsafe { free_bytes(ptr, size as usize) };
+}
 
-    fn use_system_memory(&self) -> bool {
-        self.flags & 0x04 != 0
+fn render(img: &PidImage, image: &mut OutputImage) {
+    let (width, height) = img.dimensions();
+    let flags = img.flags();
+    let pixels = img.pixels();
+    let source_index = flags.source_index();
+    let transparent_index = img.transparent_index();
+    let transparent_color = match TRANSPARENT_COLOR.load(Ordering::Relaxed) {
+        rgb @ 0..=0xFFFFFF => Some(Rgb { r: (rgb >> 16) as u8, g: (rgb >> 8) as u8, b: rgb as u8 }),
+        _ => None,
+    };
+    match img.palette() {
+        Some(palette) => {
+            let mut colors = *palette;
+            for (i, c) in colors.iter_mut().enumerate() {
+                if transparent_index == Some(i as u8) || transparent_color == Some(Rgb { r: c.r, g: c.g, b: c.b }) {
+                    *c = Rgba { r: 0, g: 0, b: 0, a: 0 };
+                }
+            }
+            for y in 0..height {
+                for x in 0..width {
+                    let color = colors[pixels[source_index(width, height, x, y)] as usize];
+                    image.set_pixel((y * width + x) as usize, color.r, color.g, color.b, color.a);
+                }
+            }
+        }
+        None => {
+            let bytes_per_pixel = flags.bytes_per_pixel();
+            let read_color = flags.color_reader();
+            for y in 0..height {
+                for x in 0..width {
+                    let p = source_index(width, height, x, y) * bytes_per_pixel;
+                    let (color, a) = read_color(&pixels[p..p + bytes_per_pixel]);
+                    let i = (y * width + x) as usize;
+                    if transparent_color == Some(color) {
+                        image.set_pixel(i, 0, 0, 0, 0);
+                    } else {
+                        image.set_pixel(i, color.r, color.g, color.b, a);
+                    }
+                }
+            }
+        }
     }
+}
+
+// The output image is allocated before the pixel buffer so that the latter,
+// only needed while rendering, is released on top of the host's allocator
+// when the decoded image is dropped.
+fn write_pid_to_canvas_image_data_from<S: ByteSource>(source: &mut S) -> Result<&'static mut [u8], PidError> {
+    let header = decode_pid_header(source)?;
+    let (width, height) = header.dimensions();
+    let mut image = OutputImage::from_canvas_with_dimensions(width, height);
+    let pixels = Buffer::new(header.pixels_len());
+    let options = DecodeOptions {
+        lenient: LENIENT.load(Ordering::Relaxed),
+        transparent_index: u8::try_from(TRANSPARENT_INDEX.load(Ordering::Relaxed)).ok(),
+    };
+    let img = decode_pid_body(source, header, pixels, options)?;
+    render(&img, &mut image);
+    drop(img);
+    Ok(image.buffer.into_raw())
+}
+
+fn write_host_pid_to_canvas_image_data() -> Result<&'static mut [u8], PidError> {
+    LAST_IMAGE_LEN.store(0, Ordering::Relaxed);
+    let image = match host_pid_data() {
+        Some(data) => write_pid_to_canvas_image_data_from(&mut SliceSource::new(data)),
+        None => write_pid_to_canvas_image_data_from(&mut PidDataCursor::new()),
+    }?;
+    LAST_IMAGE_LEN.store(image.len() as u32, Ordering::Relaxed);
+    Ok(image)
+}
 
-    fn is_fliped_horizontally(&self) -> bool {
-        self.flags & 0x08 != 0
+// Status codes reported to the host, 0 meaning success.
+fn status(error: PidError) -> u32 {
+    match error {
+        PidError::UnexpectedEof => 1,
+        PidError::InvalidDimensions { .. } => 2,
+        PidError::PixelCountMismatch { .. } => 3,
+        PidError::TruncatedPalette { .. } => 4,
+        PidError::TooManyColors => 5,
+        PidError::InvalidLightCount { .. } => 6,
+        #[cfg(feature = "std")]
+        PidError::Io { .. } => 7,
     }
+}
 
-    fn is_fliped_vertically(&self) -> bool {
-        self.flags & 0x10 != 0
+#[export_name = "write_pid_to_canvas_image_data"]
+pub extern "C" fn write_pid_to_canvas_image_data() -> *mut u8 {
+    match write_host_pid_to_canvas_image_data() {
+        Ok(image) => image.as_mut_ptr(),
+        Err(_) => ptr::null_mut(),
     }
+}
 
-    fn compression_method(&self) -> CompressionMethod {
-        if self.flags & 0x20 == 0 {
-            CompressionMethod::Default
-        } else {
-            CompressionMethod::RunLengthEncoding
+// Decodes like write_pid_to_canvas_image_data, but returns a pointer to a
+// status, pointer, length record of u32 values. The pointer and length are 0
+// unless the status is; the record is overwritten by the next call.
+#[export_name = "decode_pid_to_canvas_image_data"]
+pub extern "C" fn decode_pid_to_canvas_image_data() -> *const u32 {
+    let (status, ptr, len) = match write_host_pid_to_canvas_image_data() {
+        Ok(image) => (0, image.as_mut_ptr() as usize as u32, image.len() as u32),
+        Err(error) => (status(error), 0, 0),
+    };
+    DECODE_RESULT[0].store(status, Ordering::Relaxed);
+    DECODE_RESULT[1].store(ptr, Ordering::Relaxed);
+    DECODE_RESULT[2].store(len, Ordering::Relaxed);
+    DECODE_RESULT.as_ptr() as *const u32
+}
+
+// Decodes like write_pid_to_canvas_image_data, but hands the image to the
+// host's put_image_data and releases it once that returns, so the host never
+// has to free it. Returns the status of the decode. put_image_data is a
+// required import even for hosts that never call this.
+#[export_name = "put_pid_to_canvas_image_data"]
+pub extern "C" fn put_pid_to_canvas_image_data() -> u32 {
+    match write_host_pid_to_canvas_image_data() {
+        Ok(image) => {
+            unsafe {
+                put_image_data(image.as_ptr(), image.len() as u32);
+                free_bytes(image.as_mut_ptr(), image.len());
+            }
+            0
         }
+        Err(error) => status(error),
     }
+}
 
-    fn has_lights(&self) -> bool {
-        self.flags & 0x40 != 0
+// Reads only the header and returns the width in the high 32 bits and the
+// height in the low 32 bits, or 0 when the header is invalid.
+#[export_name = "get_pid_dimensions"]
+pub extern "C" fn get_pid_dimensions() -> u64 {
+    let header = match host_pid_data() {
+        Some(data) => decode_pid_header(&mut SliceSource::new(data)),
+        None => decode_pid_header(&mut PidDataCursor::new()),
+    };
+    match header {
+        Ok(header) => {
+            let (width, height) = header.dimensions();
+            (width as u64) << 32 | height as u64
+        }
+        Err(_) => 0,
     }
+}
+
+// Makes the given palette index transparent in place of the flag-driven rule
+// (index 0 when use_transparency is set). Pass -1 to restore that default.
+#[export_name = "set_transparent_index"]
+pub extern "C" fn set_transparent_index(index: i32) {
+    TRANSPARENT_INDEX.store(index, Ordering::Relaxed);
+}
+
+// Makes every pixel of the given 0xRRGGBB color transparent, for palette and
+// true-color images alike. Pass -1 to disable the color key.
+#[export_name = "set_transparent_color"]
+pub extern "C" fn set_transparent_color(rgb: i32) {
+    TRANSPARENT_COLOR.store(rgb, Ordering::Relaxed);
+}
+
+// Lets write_pid_to_canvas_image_data render images whose palette is cut
+// short, with the missing entries opaque black.
+#[export_name = "set_lenient"]
+pub extern "C" fn set_lenient(lenient: bool) {
+    LENIENT.store(lenient, Ordering::Relaxed);
+}
 
-    fn has_palette(&self) -> bool {
-        self.flags & 0x80 != 0
+#[export_name = "last_image_len"]
+pub extern "C" fn last_image_len() -> u32 {
+    LAST_IMAGE_LEN.load(Ordering::Relaxed)
+}
+
+/// Encodes the `width * height` RGBA pixels at `rgba` into a palette PID,
+/// quantizing images with more than 256 colors, and returns a pointer to it,
+/// or null when the dimensions are invalid or `rgba` is null.
+/// The host releases the result with `dealloc(ptr, last_pid_len())`.
+///
+/// # Safety
+///
+/// `rgba` must point to `4 * width * height` readable bytes.
+#[export_name = "encode_rgba_to_pid"]
+pub unsafe extern "C" fn encode_rgba_to_pid(rgba: *const u8, width: u32, height: u32) -> *mut u8 {
+    LAST_PID_LEN.store(0, Ordering::Relaxed);
+    let Some(len) = (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(4)) else {
+        return ptr::null_mut();
+    };
+    if len == 0 || rgba.is_null() {
+        return ptr::null_mut();
     }
+    let rgba = unsafe { slice::from_raw_parts(rgba, len) };
+    let Ok(pid) = encode_pid(rgba, width, height, EncodeOptions::default()) else {
+        return ptr::null_mut();
+    };
+    let pid = pid.into_raw();
+    LAST_PID_LEN.store(pid.len() as u32, Ordering::Relaxed);
+    pid.as_mut_ptr()
 }
 
-struct PidImage {
-    id: i32,
-    flags: ImageFlags,
-    width: u32,
-    height: u32,
-    user_values: [i32; 4],
-    pixels: &'static [u8],
-    palette: Option<[Rgb; 256]>,
+#[export_name = "last_pid_len"]
+pub extern "C" fn last_pid_len() -> u32 {
+    LAST_PID_LEN.load(Ordering::Relaxed)
 }
 
-enum CompressionMethod { Default, RunLengthEncoding }
+// Stands in for the host outside WASM: memory comes from the global
+// allocator and the PID data is set per thread by each test.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::tests::{gray_pid, palette, pid};
+    use std::alloc::Layout;
+    use std::cell::{Cell, RefCell};
+    use std::sync::{Mutex, MutexGuard};
+    use std::vec::Vec;
+
+    std::thread_local! {
+        static PID_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        static PID_DATA_SHARED: Cell<bool> = const { Cell::new(false) };
+        static PUT_IMAGE: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // Shared data is read in place through get_pid_data_ptr, the rest
+    // through the get_pid_data_* accessors.
+    pub(crate) fn set_pid_data(data: &[u8], shared: bool) {
+        PID_DATA.set(data.to_vec());
+        PID_DATA_SHARED.set(shared);
+    }
+
+    pub(crate) fn put_image() -> Vec<u8> {
+        PUT_IMAGE.take()
+    }
+
+    // Serializes the tests going through the exports, whose settings and
+    // results are process-wide.
+    pub(crate) fn host() -> MutexGuard<'static, ()> {
+        static HOST: Mutex<()> = Mutex::new(());
+        HOST.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn layout(size: u32) -> Layout {
+        Layout::from_size_align(size as usize, 1).unwrap()
+    }
 
-fn decompress_default(data: &mut PidDataCursor, pixels: &mut Buffer, pixels_count: usize) {
-    let mut pixel = 0;
-    while pixel < pixels_count {
-        let n: u8;
-        let b: u8;
-        let a = data.next_u8();
-        if a > 192 {
-            n = a - 192;
-            b = data.next_u8();
-        } else {
-            n = 1;
-            b = a;
+    #[no_mangle]
+    extern "C" fn alloc(size: u32) -> *mut u8 {
+        if size == 0 {
+            return ptr::NonNull::dangling().as_ptr();
         }
-        for _ in 0..n {
-            pixels.write_u8(pixel, b);
-            pixel += 1;
+        unsafe { std::alloc::alloc(layout(size)) }
+    }
+
+    #[no_mangle]
+    extern "C" fn free_memory(ptr: *mut u8, size: u32) {
+        if size > 0 {
+            unsafe { std::alloc::dealloc(ptr, layout(size)) };
         }
     }
-}
 
-fn decompress_run_length_encoding(data: &mut PidDataCursor, pixels: &mut Buffer, pixels_count: usize) {
-    let mut pixel = 0;
-    while pixel < pixels_count {
-        let a = data.next_u8();
-        if a > 128 {
-            let j = a - 128;
-            for _ in 0..j {
-                pixels.write_u8(pixel, 0);
-                pixel += 1;
-            }
-        } else {
-            for _ in 0..a {
-                let b = data.next_u8();
-                pixels.write_u8(pixel, b);
-                pixel += 1;
-            }
+    #[no_mangle]
+    extern "C" fn get_pid_data_u8(offset: u32) -> u8 {
+        PID_DATA.with_borrow(|data| data[offset as usize])
+    }
+
+    #[no_mangle]
+    extern "C" fn get_pid_data_u32_le(offset: u32) -> u32 {
+        let offset = offset as usize;
+        PID_DATA.with_borrow(|data| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()))
+    }
+
+    #[no_mangle]
+    extern "C" fn get_pid_data_i32_le(offset: u32) -> i32 {
+        get_pid_data_u32_le(offset) as i32
+    }
+
+    #[no_mangle]
+    extern "C" fn get_pid_data_len() -> u32 {
+        PID_DATA.with_borrow(|data| data.len() as u32)
+    }
+
+    #[no_mangle]
+    extern "C" fn get_pid_data_ptr() -> *const u8 {
+        if !PID_DATA_SHARED.get() {
+            return ptr::null();
         }
+        PID_DATA.with_borrow(|data| data.as_ptr())
+    }
+
+    #[no_mangle]
+    extern "C" fn put_image_data(ptr: *const u8, len: u32) {
+        PUT_IMAGE.set(unsafe { slice::from_raw_parts(ptr, len as usize) }.to_vec());
+    }
+
+    // Renders through the exports and returns the pixels past the header.
+    fn canvas(data: &[u8]) -> Vec<u8> {
+        set_pid_data(data, false);
+        assert_eq!(put_pid_to_canvas_image_data(), 0);
+        put_image()[8..].to_vec()
     }
-}
 
-fn decode_pid() -> PidImage {
-    let mut cur = PidDataCursor { offset: 0 };
-    let id = cur.next_i32_le();
+    fn reds(canvas: &[u8]) -> Vec<u8> {
+        canvas.chunks_exact(4).map(|p| p[0]).collect()
+    }
+
+    #[test]
+    fn put_pid_hands_canvas_image_to_host() {
+        let _host = host();
+        let body = [&[0, 1][..], &palette(&[[10, 20, 30], [40, 50, 60]])].concat();
+        set_pid_data(&pid(ImageFlags::PALETTE, 2, 1, &body), false);
+        assert_eq!(put_pid_to_canvas_image_data(), 0);
+        assert_eq!(put_image(), [2, 0, 0, 0, 1, 0, 0, 0, 10, 20, 30, 255, 40, 50, 60, 255]);
+    }
+
+    #[test]
+    fn canvas_honors_horizontal_flip() {
+        let _host = host();
+        let pixels = canvas(&gray_pid(ImageFlags::FLIP_HORIZONTAL, 3, 2, &[1, 2, 3, 4, 5, 6]));
+        assert_eq!(reds(&pixels), [30, 20, 10, 60, 50, 40]);
+    }
+
+    #[test]
+    fn canvas_honors_vertical_flip() {
+        let _host = host();
+        let pixels = canvas(&gray_pid(ImageFlags::FLIP_VERTICAL, 3, 2, &[1, 2, 3, 4, 5, 6]));
+        assert_eq!(reds(&pixels), [40, 50, 60, 10, 20, 30]);
+        let flags = ImageFlags::FLIP_HORIZONTAL | ImageFlags::FLIP_VERTICAL;
+        let pixels = canvas(&gray_pid(flags, 3, 2, &[1, 2, 3, 4, 5, 6]));
+        assert_eq!(reds(&pixels), [60, 50, 40, 30, 20, 10]);
+    }
 
-    // test
-    let flags = ImageFlags { flags: cur.next_u32_le() };
-    let width = cur.next_u32_le();
-    let height = cur.next_u32_le();
-    // end test
-    let mut user_values: [i32; 4] = [0; 4];
-    user_values[0] = cur.next_i32_le();
-    user_values[1] = cur.next_i32_le();
-    user_values[2] = cur.next_i32_le();
-    user_values[3] = cur.next_i32_le();
-    let pixels_count = (width * height) as usize;
-    let mut pixels = Buffer::new(pixels_count);
+    #[test]
+    fn canvas_renders_true_color() {
+        let _host = host();
+        assert_eq!(canvas(&pid(0, 2, 1, &[10, 20, 30, 40, 50, 60])), [10, 20, 30, 255, 40, 50, 60, 255]);
+        let pixels = canvas(&pid(ImageFlags::TRANSPARENCY, 2, 1, &[1, 2, 3, 0, 4, 5, 6, 0xC1, 128]));
+        assert_eq!(pixels, [1, 2, 3, 0, 4, 5, 6, 128]);
+    }
 
-    match flags.compression_method() {
-        CompressionMethod::Default => decompress_default(&mut cur, &mut pixels, pixels_count),
-        CompressionMethod::RunLengthEncoding => decompress_run_length_encoding(&mut cur, &mut pixels, pixels_count),
+    #[test]
+    fn canvas_rejects_runs_past_the_image() {
+        let _host = host();
+        set_pid_data(&gray_pid(0, 3, 1, &[0xC5, 1]), false);
+        assert_eq!(put_pid_to_canvas_image_data(), 3);
     }
 
-    let palette = if flags.has_palette() {
-        let mut p: [Rgb; 256] = [Rgb { r: 0, g: 0, b: 0}; 256];
-        for c in &mut p {
-            c.r = cur.next_u8();
-            c.g = cur.next_u8();
-            c.b = cur.next_u8();
+    #[test]
+    fn exports_reject_zero_dimensions() {
+        let _host = host();
+        for (width, height) in [(0, 5), (5, 0)] {
+            set_pid_data(&gray_pid(0, width, height, &[1]), false);
+            assert_eq!(get_pid_dimensions(), 0);
+            assert_eq!(put_pid_to_canvas_image_data(), 2);
+            assert!(write_pid_to_canvas_image_data().is_null());
+            assert_eq!(last_image_len(), 0);
         }
-        Some(p)
-    } else {
-        None
-    };
-    
-    PidImage { id, flags, width, height, user_values, pixels: pixels.data, palette }
-}
+    }
 
-#[export_name = "write_pid_to_canvas_image_data"]
-pub extern "C" fn write_pid_to_canvas_image_data() -> *mut u8 {
-    let img = decode_pid();
-    let mut image = OutputImage::from_canvas_with_dimensions(img.width, img.height);
-    let pix_count = img.width * img.height;
-    if let Some(palette) = img.palette {
-        for i in 0..pix_count {
-            let pixel = img.pixels[i as usize];
-            if img.flags.use_transparency() && pixel == 0 {
-                image.set_pixel(i as usize, 0, 0, 0, 0);
-            } else {
-                let color = palette[pixel as usize];
-                image.set_pixel(i as usize, color.r, color.g, color.b, 255);
-            }
+    #[test]
+    fn lenient_canvas_keeps_truncated_palette() {
+        let _host = host();
+        let bytes: Vec<u8> = (0..700).map(|i| i as u8).collect();
+        let data = pid(ImageFlags::PALETTE, 2, 1, &[&[5, 0xC1, 255][..], &bytes].concat());
+        set_pid_data(&data, false);
+        assert_eq!(put_pid_to_canvas_image_data(), 4);
+        set_lenient(true);
+        let pixels = canvas(&data);
+        set_lenient(false);
+        assert_eq!(pixels, [15, 16, 17, 255, 0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn run_length_skips_are_transparent_only_with_the_flag() {
+        let _host = host();
+        let body = [&[130, 1, 1][..], &palette(&[[200, 0, 0], [0, 0, 200]])].concat();
+        let flags = ImageFlags::PALETTE | ImageFlags::RUN_LENGTH_ENCODING;
+        let pixels = canvas(&pid(flags, 3, 1, &body));
+        assert_eq!(pixels, [200, 0, 0, 255, 200, 0, 0, 255, 0, 0, 200, 255]);
+        let pixels = canvas(&pid(flags | ImageFlags::TRANSPARENCY, 3, 1, &body));
+        assert_eq!(pixels, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 200, 255]);
+    }
+
+    #[test]
+    fn host_sources_and_library_decode_alike() {
+        let _host = host();
+        let images = [
+            gray_pid(ImageFlags::FLIP_HORIZONTAL | ImageFlags::TRANSPARENCY, 3, 2, &[0xC2, 0, 3, 4, 5, 6]),
+            gray_pid(ImageFlags::RUN_LENGTH_ENCODING, 4, 1, &[0x81, 3, 1, 2, 3]),
+            pid(ImageFlags::TRANSPARENCY, 1, 2, &[1, 2, 3, 0, 4, 5, 6, 7]),
+        ];
+        for data in images {
+            let library: Vec<u8> = decode_pid_bytes(&data).unwrap().pixels_rgba().flat_map(|(_, _, c)| [c.r, c.g, c.b, c.a]).collect();
+            let cursor = canvas(&data);
+            set_pid_data(&data, true);
+            assert_eq!(put_pid_to_canvas_image_data(), 0);
+            assert_eq!(put_image()[8..], cursor);
+            assert_eq!(cursor, library);
         }
     }
-    image.buffer.data.as_mut_ptr()
+
+    #[test]
+    fn encode_export_rejects_null_and_empty_input() {
+        let _host = host();
+        assert!(unsafe { encode_rgba_to_pid(ptr::null(), 0, 0) }.is_null());
+        assert!(unsafe { encode_rgba_to_pid(ptr::null(), 2, 2) }.is_null());
+        assert_eq!(last_pid_len(), 0);
+    }
 }
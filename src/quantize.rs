@@ -0,0 +1,85 @@
+use crate::Buffer;
+use crate::decode::Rgb;
+
+#[derive(Clone, Copy)]
+struct ColorBox {
+    start: usize,
+    end: usize,
+    channel: usize,
+    range: u8,
+}
+
+impl ColorBox {
+    fn new(colors: &[[u8; 3]], start: usize, end: usize) -> ColorBox {
+        let (mut min, mut max) = ([255u8; 3], [0u8; 3]);
+        for c in &colors[start..end] {
+            for k in 0..3 {
+                min[k] = min[k].min(c[k]);
+                max[k] = max[k].max(c[k]);
+            }
+        }
+        let channel = (0..3).max_by_key(|&k| max[k] - min[k]).unwrap_or(0);
+        ColorBox { start, end, channel, range: max[channel] - min[channel] }
+    }
+
+    fn average(&self, colors: &[[u8; 3]]) -> Rgb {
+        let mut sum = [0u64; 3];
+        for c in &colors[self.start..self.end] {
+            for k in 0..3 {
+                sum[k] += c[k] as u64;
+            }
+        }
+        let len = (self.end - self.start) as u64;
+        Rgb { r: (sum[0] / len) as u8, g: (sum[1] / len) as u8, b: (sum[2] / len) as u8 }
+    }
+}
+
+// Median cut over the opaque pixels of `rgba`: the box with the widest channel
+// is sorted along it and cut at its median until there are as many boxes as
+// palette entries or no box can be cut further. Each box then contributes its
+// average color. Returns the number of colors written to `palette`.
+pub(crate) fn median_cut(rgba: &[u8], palette: &mut [Rgb]) -> usize {
+    let opaque = rgba.chunks_exact(4).filter(|p| p[3] != 0).count();
+    if opaque == 0 || palette.is_empty() {
+        return 0;
+    }
+    let buffer = Buffer::new(3 * opaque);
+    let (colors, _) = buffer.data.as_chunks_mut::<3>();
+    for (c, p) in colors.iter_mut().zip(rgba.chunks_exact(4).filter(|p| p[3] != 0)) {
+        c.copy_from_slice(&p[..3]);
+    }
+
+    let mut boxes = [ColorBox { start: 0, end: 0, channel: 0, range: 0 }; 256];
+    boxes[0] = ColorBox::new(colors, 0, opaque);
+    let mut len = 1;
+    while len < palette.len().min(boxes.len()) {
+        let Some(i) = (0..len).filter(|&i| boxes[i].range > 0).max_by_key(|&i| boxes[i].range) else {
+            break;
+        };
+        let b = boxes[i];
+        colors[b.start..b.end].sort_unstable_by_key(|c| c[b.channel]);
+        let median = b.start + (b.end - b.start) / 2;
+        boxes[i] = ColorBox::new(colors, b.start, median);
+        boxes[len] = ColorBox::new(colors, median, b.end);
+        len += 1;
+    }
+    for (color, b) in palette.iter_mut().zip(&boxes[..len]) {
+        *color = b.average(colors);
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn median_cut_skips_transparent_pixels() {
+        let rgba = [200, 100, 0, 255, 0, 0, 0, 0, 10, 20, 30, 255];
+        let mut palette = [Rgb { r: 0, g: 0, b: 0 }; 4];
+        assert_eq!(median_cut(&rgba, &mut palette), 2);
+        palette[..2].sort_unstable_by_key(|c| c.r);
+        assert_eq!(palette[..2], [Rgb { r: 10, g: 20, b: 30 }, Rgb { r: 200, g: 100, b: 0 }]);
+        assert_eq!(median_cut(&rgba[4..8], &mut palette), 0);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::decode::{PidError, Rgb};
+
+// Post-processing passes over RGBA pixels in display order, as yielded by
+// PixelsRgba or written by the canvas exports past their 8-byte header.
+
+// Complements the color of every pixel but the fully transparent ones,
+// leaving alpha untouched.
+pub fn invert_rgba(rgba: &mut [u8]) {
+    for p in rgba.chunks_exact_mut(4) {
+        if p[3] != 0 {
+            for c in &mut p[..3] {
+                *c = 255 - *c;
+            }
+        }
+    }
+}
+
+// Scales each color channel by alpha / 255, rounding to nearest, for
+// consumers expecting premultiplied alpha. Fully transparent pixels become
+// (0, 0, 0, 0) and opaque ones are left as they are.
+pub fn premultiply_rgba(rgba: &mut [u8]) {
+    for p in rgba.chunks_exact_mut(4) {
+        let a = p[3] as u32;
+        for c in &mut p[..3] {
+            *c = ((*c as u32 * a + 127) / 255) as u8;
+        }
+    }
+}
+
+// Converts to gray and alpha byte pairs, weighting the channels with the
+// Rec. 601 luma coefficients. `out` takes two bytes per pixel; pixels that
+// do not fit are left out.
+pub fn luma_alpha(rgba: &[u8], out: &mut [u8]) {
+    for (p, q) in rgba.chunks_exact(4).zip(out.chunks_exact_mut(2)) {
+        let luma = 299 * p[0] as u32 + 587 * p[1] as u32 + 114 * p[2] as u32;
+        q[0] = ((luma + 500) / 1000) as u8;
+        q[1] = p[3];
+    }
+}
+
+// Maps the color channels of every pixel but the fully transparent ones
+// through `table`, leaving alpha untouched.
+pub fn map_channels(rgba: &mut [u8], table: &[u8; 256]) {
+    for p in rgba.chunks_exact_mut(4) {
+        if p[3] != 0 {
+            for c in &mut p[..3] {
+                *c = table[*c as usize];
+            }
+        }
+    }
+}
+
+// Lookup table for map_channels applying out = 255 * (in / 255)^(1 / gamma).
+// Powers need the float functions of std.
+#[cfg(feature = "std")]
+pub fn gamma_table(gamma: f32) -> [u8; 256] {
+    let mut table = [0; 256];
+    for (i, t) in table.iter_mut().enumerate() {
+        *t = (255.0 * (i as f32 / 255.0).powf(1.0 / gamma)).round() as u8;
+    }
+    table
+}
+
+// Composites every pixel over an opaque `background` with the "over"
+// operator, leaving the image fully opaque.
+pub fn composite_over(rgba: &mut [u8], background: Rgb) {
+    let background = [background.r, background.g, background.b];
+    for p in rgba.chunks_exact_mut(4) {
+        let a = p[3] as u32;
+        for (c, b) in p[..3].iter_mut().zip(background) {
+            *c = ((*c as u32 * a + b as u32 * (255 - a) + 127) / 255) as u8;
+        }
+        p[3] = 255;
+    }
+}
+
+fn check_len(rgba: &[u8], width: u32, height: u32) -> Result<(), PidError> {
+    if width == 0 || height == 0 || rgba.len() as u64 != 4 * width as u64 * height as u64 {
+        return Err(PidError::InvalidDimensions { width, height });
+    }
+    Ok(())
+}
+
+// Upscales by repeating each pixel into a factor x factor block. `out` must
+// hold the width * factor by height * factor result.
+pub fn scale_rgba(rgba: &[u8], width: u32, height: u32, factor: u32, out: &mut [u8]) -> Result<(), PidError> {
+    check_len(rgba, width, height)?;
+    let (Some(out_width), Some(out_height)) = (width.checked_mul(factor), height.checked_mul(factor)) else {
+        return Err(PidError::InvalidDimensions { width, height });
+    };
+    check_len(out, out_width, out_height)?;
+    let (row, factor) = (4 * width as usize, factor as usize);
+    for (y, out_row) in out.chunks_exact_mut(row * factor).enumerate() {
+        let source = &rgba[y / factor * row..][..row];
+        for (x, p) in out_row.chunks_exact_mut(4).enumerate() {
+            p.copy_from_slice(&source[x / factor * 4..][..4]);
+        }
+    }
+    Ok(())
+}
+
+// Clockwise quarter turns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation { Rotate90, Rotate180, Rotate270 }
+
+// Rotates into `out`, which must hold as many pixels as `rgba`, and returns
+// its dimensions, swapped for quarter turns.
+pub fn rotate_rgba(rgba: &[u8], width: u32, height: u32, rotation: Rotation, out: &mut [u8]) -> Result<(u32, u32), PidError> {
+    check_len(rgba, width, height)?;
+    check_len(out, width, height)?;
+    let (out_width, out_height) = match rotation {
+        Rotation::Rotate180 => (width, height),
+        Rotation::Rotate90 | Rotation::Rotate270 => (height, width),
+    };
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let (sx, sy) = match rotation {
+                Rotation::Rotate90 => (y, height - 1 - x),
+                Rotation::Rotate180 => (width - 1 - x, height - 1 - y),
+                Rotation::Rotate270 => (width - 1 - y, x),
+            };
+            let (i, j) = (4 * (y * out_width + x) as usize, 4 * (sy * width + sx) as usize);
+            out[i..i + 4].copy_from_slice(&rgba[j..j + 4]);
+        }
+    }
+    Ok((out_width, out_height))
+}
+
+// Mirrors in place, the same way the flip flags do when decoding.
+fn flip_rgba(rgba: &mut [u8], width: u32, horizontal: bool, vertical: bool) {
+    let row = 4 * width as usize;
+    if horizontal {
+        for pixels in rgba.chunks_exact_mut(row) {
+            pixels.as_chunks_mut::<4>().0.reverse();
+        }
+    }
+    if vertical {
+        let rows = rgba.len() / row;
+        for y in 0..rows / 2 {
+            let (top, bottom) = rgba.split_at_mut((rows - 1 - y) * row);
+            top[y * row..(y + 1) * row].swap_with_slice(&mut bottom[..row]);
+        }
+    }
+}
+
+// The passes run by apply_transforms, always in this order: the flips, the
+// rotation, the scaling, then the color passes, which are invert, the
+// channel table, the background and premultiply.
+#[derive(Clone, Copy, Default)]
+pub struct Transforms<'a> {
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub rotation: Option<Rotation>,
+    // None leaves the size unchanged.
+    pub scale: Option<u32>,
+    pub invert: bool,
+    pub table: Option<&'a [u8; 256]>,
+    pub background: Option<Rgb>,
+    pub premultiply: bool,
+}
+
+// Runs `transforms` over `rgba`, which is overwritten along the way, and
+// writes the result to `out`, which must hold the scaled image. Returns the
+// dimensions of the result.
+pub fn apply_transforms(rgba: &mut [u8], width: u32, height: u32, transforms: &Transforms, out: &mut [u8]) -> Result<(u32, u32), PidError> {
+    check_len(rgba, width, height)?;
+    let factor = transforms.scale.unwrap_or(1);
+    let (Some(out_width), Some(out_height)) = (width.checked_mul(factor), height.checked_mul(factor)) else {
+        return Err(PidError::InvalidDimensions { width, height });
+    };
+    check_len(out, out_width, out_height)?;
+
+    flip_rgba(rgba, width, transforms.flip_horizontal, transforms.flip_vertical);
+    let (mut width, mut height) = (width, height);
+    if let Some(rotation) = transforms.rotation {
+        let rotated = &mut out[..rgba.len()];
+        (width, height) = rotate_rgba(rgba, width, height, rotation, rotated)?;
+        rgba.copy_from_slice(rotated);
+    }
+    scale_rgba(rgba, width, height, factor, out)?;
+
+    if transforms.invert {
+        invert_rgba(out);
+    }
+    if let Some(table) = transforms.table {
+        map_channels(out, table);
+    }
+    if let Some(background) = transforms.background {
+        composite_over(out, background);
+    }
+    if transforms.premultiply {
+        premultiply_rgba(out);
+    }
+    Ok((width * factor, height * factor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::tests::{gray_pid, pid};
+    use crate::decode::{decode_pid_bytes, ImageFlags};
+    use std::vec::Vec;
+
+    #[test]
+    fn invert_skips_transparent_pixels() {
+        let mut rgba = [0, 100, 255, 128, 1, 2, 3, 0];
+        invert_rgba(&mut rgba);
+        assert_eq!(rgba, [255, 155, 0, 128, 1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn scale_repeats_pixels() {
+        let rgba = [1, 1, 1, 1, 2, 2, 2, 2];
+        let mut out = [0; 4 * 8];
+        scale_rgba(&rgba, 2, 1, 2, &mut out).unwrap();
+        assert_eq!(out.chunks_exact(4).map(|p| p[0]).collect::<Vec<_>>(), [1, 1, 2, 2, 1, 1, 2, 2]);
+        assert!(matches!(scale_rgba(&rgba, 2, 1, 0, &mut []), Err(PidError::InvalidDimensions { .. })));
+        assert!(matches!(scale_rgba(&rgba, 2, 1, 3, &mut out), Err(PidError::InvalidDimensions { .. })));
+    }
+
+    #[test]
+    fn rotate_swaps_dimensions() {
+        // 1 2 3
+        // 4 5 6
+        let rgba: Vec<u8> = (1..=6).flat_map(|i| [i; 4]).collect();
+        let mut out = [0; 4 * 6];
+        let turns = [
+            (Rotation::Rotate90, (2, 3), [4, 1, 5, 2, 6, 3]),
+            (Rotation::Rotate180, (3, 2), [6, 5, 4, 3, 2, 1]),
+            (Rotation::Rotate270, (2, 3), [3, 6, 2, 5, 1, 4]),
+        ];
+        for (rotation, dimensions, pixels) in turns {
+            assert_eq!(rotate_rgba(&rgba, 3, 2, rotation, &mut out).unwrap(), dimensions);
+            assert_eq!(out.chunks_exact(4).map(|p| p[0]).collect::<Vec<_>>(), pixels);
+        }
+    }
+
+    #[test]
+    fn premultiply_scales_by_alpha() {
+        let mut rgba = [200, 100, 50, 128, 9, 8, 7, 0];
+        premultiply_rgba(&mut rgba);
+        assert_eq!(rgba, [100, 50, 25, 128, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn luma_weights_channels_and_keeps_alpha() {
+        let rgba = [255, 0, 0, 255, 0, 255, 0, 128, 255, 255, 255, 0];
+        let mut out = [0; 6];
+        luma_alpha(&rgba, &mut out);
+        assert_eq!(out, [76, 255, 150, 128, 255, 0]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn gamma_lightens_mid_gray() {
+        let mut rgba = [0, 128, 255, 128, 128, 128, 128, 0];
+        map_channels(&mut rgba, &gamma_table(1.0));
+        assert_eq!(rgba, [0, 128, 255, 128, 128, 128, 128, 0]);
+        map_channels(&mut rgba, &gamma_table(2.2));
+        assert_eq!(rgba, [0, 186, 255, 128, 128, 128, 128, 0]);
+    }
+
+    #[test]
+    fn composite_blends_half_alpha_over_white() {
+        let mut rgba = [255, 0, 0, 128, 1, 2, 3, 0, 1, 2, 3, 255];
+        composite_over(&mut rgba, Rgb { r: 255, g: 255, b: 255 });
+        assert_eq!(rgba, [255, 127, 127, 255, 255, 255, 255, 255, 1, 2, 3, 255]);
+    }
+
+    // With binary transparency premultiplying only clears the transparent
+    // pixels; palette alpha is where it starts to matter.
+    #[test]
+    fn premultiply_decoded_images() {
+        let rgba = |data: &[u8]| -> Vec<u8> {
+            let image = decode_pid_bytes(data).unwrap();
+            image.pixels_rgba().flat_map(|(_, _, c)| [c.r, c.g, c.b, c.a]).collect()
+        };
+        let mut pixels = rgba(&gray_pid(ImageFlags::TRANSPARENCY, 2, 1, &[0, 5]));
+        let straight = pixels.clone();
+        premultiply_rgba(&mut pixels);
+        assert_eq!(pixels, straight);
+        assert_eq!(pixels, [0, 0, 0, 0, 50, 50, 50, 255]);
+
+        let palette: Vec<u8> = [[200u8, 100, 0, 51]].repeat(256).concat();
+        let mut pixels = rgba(&pid(ImageFlags::PALETTE | ImageFlags::PALETTE_ALPHA, 1, 1, &[&[1][..], &palette].concat()));
+        premultiply_rgba(&mut pixels);
+        assert_eq!(pixels, [40, 20, 0, 51]);
+    }
+
+    #[test]
+    fn transforms_apply_in_order() {
+        // 1 2 3
+        // 4 5 6
+        let mut rgba: Vec<u8> = (1..=6).flat_map(|i| [i, 0, 0, 255]).collect();
+        let mut out = [0; 4 * 24];
+        let transforms = Transforms {
+            flip_horizontal: true,
+            rotation: Some(Rotation::Rotate90),
+            scale: Some(2),
+            invert: true,
+            ..Transforms::default()
+        };
+        assert_eq!(apply_transforms(&mut rgba, 3, 2, &transforms, &mut out).unwrap(), (4, 6));
+        let reds: Vec<u8> = out.chunks_exact(4).map(|p| 255 - p[0]).collect();
+        assert_eq!(reds, [[6, 6, 3, 3].repeat(2), [5, 5, 2, 2].repeat(2), [4, 4, 1, 1].repeat(2)].concat());
+        assert!(out.chunks_exact(4).all(|p| p[1..] == [255, 255, 255]));
+
+        let transforms = Transforms { flip_vertical: true, scale: Some(0), ..Transforms::default() };
+        assert!(matches!(apply_transforms(&mut rgba, 3, 2, &transforms, &mut []), Err(PidError::InvalidDimensions { .. })));
+    }
+}